@@ -0,0 +1,81 @@
+//! Sampling several frames of animated wallpapers
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames};
+
+/// Up to `count` frames of an animated GIF or PNG, spread evenly over the
+/// animation, so that the theme reflects all of it rather than its first
+/// frame.
+///
+/// Returns `None` for still images and when fewer than 2 frames are asked for,
+/// so that the wallpaper is decoded as usual.
+pub fn frames(path: &Path, count: usize) -> anyhow::Result<Option<Vec<DynamicImage>>> {
+    if count < 2 {
+        return Ok(None);
+    }
+    // the frames are counted first, to avoid holding all of them at once
+    let Some(all) = open(path)? else {
+        return Ok(None);
+    };
+    let total = all.count();
+    if total < 2 {
+        return Ok(None);
+    }
+
+    let picked = spread(total, count);
+    let last = picked.last().copied().unwrap_or_default();
+    let Some(all) = open(path)? else {
+        return Ok(None);
+    };
+    let frames = all
+        .take(last + 1)
+        .enumerate()
+        .filter(|(i, _)| picked.contains(i))
+        .map(|(_, frame)| frame.map(|f| DynamicImage::ImageRgba8(f.into_buffer())))
+        .collect::<Result<Vec<_>, _>>()?;
+    tracing::debug!("Sampling {} of the {} frames of {}", frames.len(), total, path.display());
+    Ok(Some(frames))
+}
+
+/// The frames of an animated GIF or PNG, `None` for other images
+fn open(path: &Path) -> anyhow::Result<Option<Frames<'static>>> {
+    let ext = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("gif") => {
+            let decoder = GifDecoder::new(BufReader::new(File::open(path)?))?;
+            Ok(Some(decoder.into_frames()))
+        },
+        Some("png" | "apng") => {
+            let decoder = PngDecoder::new(BufReader::new(File::open(path)?))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            Ok(Some(decoder.apng()?.into_frames()))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// `count` distinct indices spread evenly over `total` frames, starting with
+/// the first, or all of them if there are fewer
+fn spread(total: usize, count: usize) -> Vec<usize> {
+    let count = count.min(total);
+    (0..count).map(|i| i * total / count).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_spread_over_the_animation() {
+        assert_eq!(spread(10, 3), vec![0, 3, 6]);
+        assert_eq!(spread(4, 4), vec![0, 1, 2, 3]);
+        assert_eq!(spread(2, 5), vec![0, 1]);
+    }
+}
@@ -0,0 +1,250 @@
+//! Import and testing of avoid lists
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use cosmic_bg_config::state::State;
+use cosmic_config::{ConfigSet, CosmicConfigEntry};
+use palette::{IntoColor, Lch, Srgb};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    changed_wallpapers, generate_theme, hex, is_near_avoid, load_config, load_config_for,
+    load_config_layers, load_state, parse_hex, MyConfig, ID,
+};
+
+/// An entry of an avoid list, either a color or the name of one of the
+/// `color_aliases`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AvoidColor {
+    Color(Srgb),
+    Alias(String),
+}
+
+impl From<Srgb> for AvoidColor {
+    fn from(c: Srgb) -> Self {
+        Self::Color(c)
+    }
+}
+
+/// Replaces the aliases in `list` with their colors. Unknown names are
+/// removed from the list and returned.
+pub fn resolve_aliases(
+    list: &mut Vec<AvoidColor>,
+    aliases: &BTreeMap<String, [f32; 3]>,
+) -> Vec<String> {
+    let mut unknown = Vec::new();
+    list.retain_mut(|entry| {
+        let AvoidColor::Alias(name) = entry else {
+            return true;
+        };
+        match aliases.get(name) {
+            Some(&[r, g, b]) => {
+                *entry = AvoidColor::Color(Srgb::new(r, g, b));
+                true
+            },
+            None => {
+                unknown.push(std::mem::take(name));
+                false
+            },
+        }
+    });
+    unknown
+}
+
+/// The colors of an avoid list, skipping any unresolved aliases
+pub fn colors(list: &[AvoidColor]) -> Vec<Srgb> {
+    list.iter()
+        .filter_map(|entry| match entry {
+            AvoidColor::Color(c) => Some(*c),
+            AvoidColor::Alias(_) => None,
+        })
+        .collect()
+}
+
+/// Parses a palette file, either a GIMP `.gpl` palette or one hex color per
+/// line. Blank lines are skipped.
+pub fn parse_palette(s: &str) -> anyhow::Result<Vec<Srgb>> {
+    let mut lines = s.lines().enumerate().peekable();
+    let gpl = lines.peek().is_some_and(|(_, l)| l.trim() == "GIMP Palette");
+    if gpl {
+        lines.next();
+    }
+
+    let mut colors = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let color = if gpl {
+            if line.starts_with('#') || line.contains(':') {
+                // comments and headers like `Name:` or `Columns:`
+                continue;
+            }
+            let channels = line
+                .split_whitespace()
+                .take(3)
+                .map(u8::from_str)
+                .collect::<Result<Vec<u8>, _>>()
+                .ok()
+                .filter(|c| c.len() == 3);
+            let Some(c) = channels else {
+                anyhow::bail!("Invalid color on line {}: {line}", i + 1);
+            };
+            Srgb::new(c[0], c[1], c[2])
+        } else {
+            Srgb::<u8>::from_str(line)
+                .map_err(|err| anyhow::anyhow!("Invalid color on line {}: {line}: {err}", i + 1))?
+        };
+        colors.push(color.into_format());
+    }
+    Ok(colors)
+}
+
+/// Appends the colors of the palette file to the chosen avoid list, skipping
+/// the colors that are already in it.
+pub fn import(file: &Path, accents: bool, is_dark: bool) -> anyhow::Result<()> {
+    let colors = parse_palette(&std::fs::read_to_string(file)?)?;
+
+    // the lists are written back, so the common colors must not be merged in
+    let user_config = cosmic_config::Config::new(ID, MyConfig::VERSION)?;
+    let mut config = load_config_layers(Some(&user_config));
+    let (key, list) = match (accents, is_dark) {
+        (true, true) => ("avoid_accents_dark", &mut config.avoid_accents_dark),
+        (true, false) => ("avoid_accents_light", &mut config.avoid_accents_light),
+        (false, true) => ("avoid_dark", &mut config.avoid_dark),
+        (false, false) => ("avoid_light", &mut config.avoid_light),
+    };
+    let mut added = 0;
+    for c in colors.into_iter().map(AvoidColor::from) {
+        if !list.contains(&c) {
+            list.push(c);
+            added += 1;
+        }
+    }
+
+    user_config.set(key, list.clone())?;
+    println!("Added {added} colors to {key}");
+    Ok(())
+}
+
+/// Generates the themes for the current wallpaper with and without `color`
+/// in the avoid lists, and reports whether the accent or the background match
+/// it in each case.
+///
+/// The themes are generated as a dry run and never written, so the test
+/// changes neither the theme nor the caches.
+pub fn test(color: &str) -> anyhow::Result<()> {
+    let color = parse_hex(color)?;
+
+    let state = load_state(&State::state()?);
+    let primary_output = load_config().primary_output;
+    let Some((output, source)) =
+        changed_wallpapers(None, &state, primary_output.as_deref()).into_iter().next()
+    else {
+        anyhow::bail!("No wallpapers found");
+    };
+    // the config the daemon themes the wallpaper with
+    let mut without = load_config_for(source);
+    // deterministic, and without touching the saved state
+    without.randomize = false;
+    without.save_results = false;
+    without.rolling_average_window = 1;
+    without.lazy_update = false;
+    without.rotate_accent_secs = None;
+    let entry = AvoidColor::Color(color);
+    for list in lists(&mut without) {
+        list.retain(|c| *c != entry);
+    }
+    let mut with = without.clone();
+    for list in lists(&mut with) {
+        list.push(entry.clone());
+    }
+
+    let tolerance = without.avoid_tolerance;
+    let matches = |c: Lch| is_near_avoid(c, &[color], &tolerance);
+    println!("testing {} on the wallpaper of {output}", hex(color));
+    for is_dark in [true, false] {
+        let before = generate_theme(output, source, is_dark, None, &without, true)?.result;
+        let after = generate_theme(output, source, is_dark, None, &with, true)?.result;
+        let picked_before =
+            [matches(before.accent.into_color()), matches(before.bg.color.into_color())];
+        let picked_after =
+            [matches(after.accent.into_color()), matches(after.bg.color.into_color())];
+
+        println!("{}:", if is_dark { "dark" } else { "light" });
+        for (i, part) in ["accent", "bg"].into_iter().enumerate() {
+            let (b, a) = if i == 0 {
+                (before.accent, after.accent)
+            } else {
+                (before.bg.color, after.bg.color)
+            };
+            println!(
+                "  {part:<6} without {}{}, with {}{}",
+                hex(b),
+                if picked_before[i] { " (matches)" } else { "" },
+                hex(a),
+                if picked_after[i] { " (matches)" } else { "" },
+            );
+        }
+        let verdict = if picked_after.contains(&true) {
+            "the color is still picked despite the avoid entry"
+        } else if picked_before.contains(&true) {
+            "the avoid entry keeps the color out of the theme"
+        } else {
+            "the color isn't picked even without the avoid entry, so it has no effect here"
+        };
+        println!("  {verdict}");
+    }
+    Ok(())
+}
+
+/// The accent and background avoid lists of both modes
+fn lists(config: &mut MyConfig) -> [&mut Vec<AvoidColor>; 4] {
+    [
+        &mut config.avoid_accents_dark,
+        &mut config.avoid_accents_light,
+        &mut config.avoid_dark,
+        &mut config.avoid_light,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hex_lines() {
+        let colors = parse_palette("#ff0000\n\n00ff00\n").unwrap();
+        assert_eq!(colors, vec![Srgb::new(1., 0., 0.), Srgb::new(0., 1., 0.)]);
+    }
+
+    #[test]
+    fn parse_gpl() {
+        let gpl = "GIMP Palette\nName: Mud\nColumns: 2\n#\n255   0   0\tRed\n  0 0 255 Blue\n";
+        let colors = parse_palette(gpl).unwrap();
+        assert_eq!(colors, vec![Srgb::new(1., 0., 0.), Srgb::new(0., 0., 1.)]);
+    }
+
+    #[test]
+    fn parse_reports_invalid_line() {
+        let err = parse_palette("#ff0000\nmud\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn aliases_resolve_and_unknown_are_dropped() {
+        let aliases = BTreeMap::from([("mud".to_string(), [0.54, 0.38, 0.28])]);
+        let mut list = vec![
+            AvoidColor::Alias("mud".into()),
+            AvoidColor::Color(Srgb::new(1., 0., 0.)),
+            AvoidColor::Alias("swamp".into()),
+        ];
+        let unknown = resolve_aliases(&mut list, &aliases);
+        assert_eq!(unknown, vec!["swamp".to_string()]);
+        assert_eq!(colors(&list), vec![Srgb::new(0.54, 0.38, 0.28), Srgb::new(1., 0., 0.)]);
+    }
+}
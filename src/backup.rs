@@ -0,0 +1,134 @@
+//! Backups of the config and the caches, to carry them between installs
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use cosmic_config::{ConfigGet, ConfigSet, CosmicConfigEntry};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{self, CacheEntry};
+use crate::{load_config_layers, user_config, BgResult, KmeanState, MyConfig, ID};
+
+/// Version of the backup format, raised whenever a backup written by this
+/// version can't be read the same way by an older one
+const BACKUP_VERSION: u32 = 1;
+
+/// The config and the cached results and centroids
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    config: MyConfig,
+    /// The saved results, by state key
+    results: BTreeMap<String, BgResult>,
+    /// The saved k-means centroids, by state key
+    kmeans: BTreeMap<String, KmeanState>,
+}
+
+/// Writes the config and all the readable cached results and centroids to
+/// `file` as JSON.
+///
+/// The config includes the system config, so that the backup restores the
+/// same settings on a machine without it.
+pub fn backup(file: &Path) -> anyhow::Result<()> {
+    let state_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)?;
+    let mut results = BTreeMap::new();
+    let mut kmeans = BTreeMap::new();
+    for entry in cache::entries()? {
+        match entry {
+            CacheEntry::Result { key, .. } => match state_config.get::<BgResult>(&key) {
+                Ok(result) => {
+                    results.insert(key, result);
+                },
+                Err(err) => tracing::warn!("Skipping the unreadable result {}: {}", key, err),
+            },
+            CacheEntry::Kmeans { key, .. } => match state_config.get::<KmeanState>(&key) {
+                Ok(state) if state.is_valid() => {
+                    kmeans.insert(key, state);
+                },
+                Ok(_) => tracing::warn!("Skipping the invalid centroids {}", key),
+                Err(err) => tracing::warn!("Skipping the unreadable centroids {}: {}", key, err),
+            },
+            CacheEntry::Other { .. } => {},
+        }
+    }
+
+    let backup = Backup {
+        version: BACKUP_VERSION,
+        config: load_config_layers(user_config().as_ref()),
+        results,
+        kmeans,
+    };
+    std::fs::write(file, serde_json::to_string_pretty(&backup)?)?;
+    println!(
+        "Backed up the config, {} results and {} centroids to {}",
+        backup.results.len(),
+        backup.kmeans.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+/// Restores the config and the caches from a backup written by [`backup`],
+/// replacing the current config and the cache entries of the same keys.
+pub fn restore(file: &Path) -> anyhow::Result<()> {
+    let backup = parse(&std::fs::read_to_string(file)?)?;
+
+    let config = cosmic_config::Config::new(ID, MyConfig::VERSION)?;
+    backup.config.write_entry(&config)?;
+
+    let state_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)?;
+    let tx = state_config.transaction();
+    for (key, result) in &backup.results {
+        tx.set(key, result)?;
+    }
+    for (key, state) in &backup.kmeans {
+        tx.set(key, state)?;
+    }
+    tx.commit()?;
+    println!(
+        "Restored the config, {} results and {} centroids from {}",
+        backup.results.len(),
+        backup.kmeans.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+/// Parses a backup, rejecting the formats of newer versions.
+fn parse(s: &str) -> anyhow::Result<Backup> {
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+    let Version { version } = serde_json::from_str(s)?;
+    if version > BACKUP_VERSION {
+        anyhow::bail!(
+            "The backup has version {version}, this version only reads up to {BACKUP_VERSION}"
+        );
+    }
+    Ok(serde_json::from_str(s)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn newer_backups_are_rejected() {
+        let backup = Backup {
+            version: BACKUP_VERSION,
+            config: MyConfig::default(),
+            results: BTreeMap::new(),
+            kmeans: BTreeMap::new(),
+        };
+        let json = serde_json::to_string(&backup).unwrap();
+        assert!(parse(&json).is_ok());
+
+        let newer = json.replacen(
+            &format!("\"version\":{BACKUP_VERSION}"),
+            &format!("\"version\":{}", BACKUP_VERSION + 1),
+            1,
+        );
+        assert!(parse(&newer).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! Timing of the extraction stages, for tuning the config
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::{icc, load_config, run_kmeans, sample_pixels, svg, DEFAULT_CLUSTERS};
+
+/// Runs the extraction on `image` `runs` times with the current config and
+/// prints the min, median and max duration of each stage.
+pub fn run(image: &Path, runs: usize) -> anyhow::Result<()> {
+    let bg_config = load_config();
+    let mut decode = Vec::with_capacity(runs);
+    let mut resize = Vec::with_capacity(runs);
+    let mut kmeans = Vec::with_capacity(runs);
+    for _ in 0..runs {
+        let start = Instant::now();
+        let img = if svg::is_svg(image) {
+            svg::rasterize(image, bg_config.sample_width)?
+        } else {
+            icc::decode(image, bg_config.use_icc_profile)?
+        };
+        decode.push(start.elapsed());
+
+        let start = Instant::now();
+        let (img, _) = sample_pixels(img, bg_config.sample_width, bg_config.square_sample)?;
+        resize.push(start.elapsed());
+
+        let start = Instant::now();
+        run_kmeans(DEFAULT_CLUSTERS, &img, &bg_config);
+        kmeans.push(start.elapsed());
+    }
+
+    println!("{} runs of {}", runs, image.display());
+    println!("{:<8} {:>10} {:>10} {:>10}", "stage", "min", "median", "max");
+    for (stage, durations) in [("decode", decode), ("resize", resize), ("k-means", kmeans)] {
+        let (min, median, max) = summary(durations);
+        println!("{:<8} {:>10.2?} {:>10.2?} {:>10.2?}", stage, min, median, max);
+    }
+    Ok(())
+}
+
+/// The min, median and max of a non-empty list of durations
+fn summary(mut durations: Vec<Duration>) -> (Duration, Duration, Duration) {
+    durations.sort_unstable();
+    (durations[0], durations[durations.len() / 2], durations[durations.len() - 1])
+}
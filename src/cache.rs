@@ -0,0 +1,288 @@
+//! Inspection of the results and k-means centroids cached in the state config
+
+use std::path::{Path, PathBuf};
+
+use cosmic_bg_config::state::State;
+use cosmic_config::ConfigGet;
+use image::{Rgb, RgbImage};
+use palette::{IntoColor, Srgb};
+
+use crate::{changed_wallpapers, hex, load_config, load_state, video, BgResult, KmeanState, ID};
+
+/// Version of the state config the caches are stored in
+pub const STATE_VERSION: u64 = 1;
+
+/// Key of the saved result of a wallpaper
+pub fn result_key(path: &Path, is_dark: bool) -> String {
+    format!("{}_{}", path_key(path), is_dark)
+}
+
+/// Key of the saved centroids of a wallpaper, shared by both modes
+pub fn kmeans_key(path: &Path) -> String {
+    format!("{}_kmeans", path_key(path))
+}
+
+/// Key of the last cluster count chosen for a wallpaper
+pub fn clusters_key(path: &Path) -> String {
+    format!("{}_clusters", path_key(path))
+}
+
+/// Key of the palettes of the recent wallpapers for `rolling_average_window`
+pub fn recent_key(is_dark: bool) -> &'static str {
+    // not suffixed with the mode like the results, which `list` would mistake
+    // it for
+    if is_dark {
+        "recent_palettes_dark"
+    } else {
+        "recent_palettes_light"
+    }
+}
+
+/// Key of the accent candidates cycled through with `rotate_accent_secs`
+pub fn rotation_key(is_dark: bool) -> &'static str {
+    if is_dark {
+        "rotation_accents_dark"
+    } else {
+        "rotation_accents_light"
+    }
+}
+
+/// Key of the result last applied in the mode, for `apply_last_on_startup`
+pub fn last_result_key(is_dark: bool) -> &'static str {
+    if is_dark {
+        "last_result_dark"
+    } else {
+        "last_result_light"
+    }
+}
+
+/// The wallpaper path as a key. Symlinks are resolved, so that a stable link
+/// repointed by a rotation tool doesn't serve the caches of its old target.
+pub(crate) fn path_key(path: &Path) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.to_string_lossy().replace('/', "_")
+}
+
+/// Size of each swatch of a thumbnail, in pixels
+const SWATCH_SIZE: u32 = 16;
+
+/// Where the thumbnail of the result saved under `key` is stored
+pub fn thumbnail_path(key: &str) -> Option<PathBuf> {
+    Some(state_dir()?.join("thumbnails").join(format!("{key}.png")))
+}
+
+/// Renders the accent, background, neutral and text colors of the result as a
+/// strip of swatches next to the saved result.
+pub fn save_thumbnail(key: &str, result: &BgResult) -> anyhow::Result<()> {
+    let Some(path) = thumbnail_path(key) else {
+        anyhow::bail!("Failed to find the state directory");
+    };
+    let swatches: Vec<Srgb<u8>> = [result.accent, result.bg.color, result.neutral]
+        .into_iter()
+        .chain(result.text)
+        .map(|c| c.into_format())
+        .collect();
+    let img = RgbImage::from_fn(SWATCH_SIZE * swatches.len() as u32, SWATCH_SIZE, |x, _| {
+        let c = swatches[(x / SWATCH_SIZE) as usize];
+        Rgb([c.red, c.green, c.blue])
+    });
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    img.save(path)?;
+    Ok(())
+}
+
+/// A cache entry, identified by the key it is stored under
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheEntry {
+    /// A saved theme, keyed by `{path}_{is_dark}`
+    Result { key: String, wallpaper: String, is_dark: bool },
+    /// Saved k-means centroids, keyed by `{path}_kmeans`
+    Kmeans { key: String, wallpaper: String },
+    /// Any other state, like the last cluster counts
+    Other { key: String },
+}
+
+impl CacheEntry {
+    fn parse(key: String) -> Self {
+        // the path separators were replaced with underscores in the key, so
+        // the wallpapers are only a best guess of the original path
+        if let Some(wallpaper) = key.strip_suffix("_kmeans") {
+            // centroids used to be saved per mode
+            let wallpaper = wallpaper
+                .strip_suffix("_true")
+                .or_else(|| wallpaper.strip_suffix("_false"))
+                .unwrap_or(wallpaper)
+                .replace('_', "/");
+            return CacheEntry::Kmeans { key, wallpaper };
+        }
+        let mode = key
+            .strip_suffix("_true")
+            .map(|w| (w, true))
+            .or_else(|| key.strip_suffix("_false").map(|w| (w, false)));
+        let Some((wallpaper, is_dark)) = mode else {
+            return CacheEntry::Other { key };
+        };
+        let wallpaper = wallpaper.replace('_', "/");
+        CacheEntry::Result { key, wallpaper, is_dark }
+    }
+}
+
+/// The directory the state config of the daemon is stored in
+pub fn state_dir() -> Option<PathBuf> {
+    let state_home =
+        std::env::var_os("XDG_STATE_HOME").map(PathBuf::from).filter(|p| p.is_absolute()).or_else(
+            || std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")),
+        )?;
+    Some(state_home.join("cosmic").join(ID).join(format!("v{STATE_VERSION}")))
+}
+
+/// `$XDG_CACHE_HOME`, or its default `~/.cache`
+pub fn cache_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+}
+
+/// The directory the daemon caches downloaded files in
+pub fn cache_dir() -> Option<PathBuf> {
+    Some(cache_home()?.join(ID))
+}
+
+/// All the entries of the state config
+pub fn entries() -> anyhow::Result<Vec<CacheEntry>> {
+    let Some(dir) = state_dir() else {
+        anyhow::bail!("Failed to find the state directory");
+    };
+    let read_dir = match std::fs::read_dir(&dir) {
+        Ok(read_dir) => read_dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut keys = Vec::new();
+    for entry in read_dir {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            keys.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    keys.sort_unstable();
+    Ok(keys.into_iter().map(CacheEntry::parse).collect())
+}
+
+/// Prints every cached result and k-means entry.
+pub fn list() -> anyhow::Result<()> {
+    let state_config = cosmic_config::Config::new_state(ID, STATE_VERSION)?;
+    for entry in entries()? {
+        match &entry {
+            CacheEntry::Result { key, wallpaper, is_dark } => {
+                let accent = state_config
+                    .get::<BgResult>(key)
+                    .map_or_else(|err| format!("unreadable: {err}"), |r| hex(r.accent));
+                println!("result  {} {wallpaper} accent {accent}", mode(*is_dark));
+            },
+            CacheEntry::Kmeans { key, wallpaper } => {
+                let centroids = state_config.get::<KmeanState>(key).map_or_else(
+                    |err| format!("unreadable: {err}"),
+                    |k| {
+                        let first = k.0.first().map(|c| hex((*c).into_color())).unwrap_or_default();
+                        format!("{} centroids, first {first}", k.0.len())
+                    },
+                );
+                println!("kmeans  both  {wallpaper} {centroids}");
+            },
+            CacheEntry::Other { key } => println!("other   {key}"),
+        }
+    }
+    Ok(())
+}
+
+fn mode(is_dark: bool) -> &'static str {
+    if is_dark {
+        "dark "
+    } else {
+        "light"
+    }
+}
+
+/// Prints which caches would be used for the current wallpaper in each mode,
+/// without applying anything.
+pub fn explain() -> anyhow::Result<()> {
+    let state = load_state(&State::state()?);
+    let bg_config = load_config();
+    let primary = bg_config.primary_output.as_deref();
+    let Some((output, source)) = changed_wallpapers(None, &state, primary).into_iter().next()
+    else {
+        anyhow::bail!("No wallpapers found");
+    };
+    let cosmic_bg_config::Source::Path(path) = source else {
+        anyhow::bail!("The wallpaper of {output} is not an image");
+    };
+    let state_config = cosmic_config::Config::new_state(ID, STATE_VERSION)?;
+
+    println!("wallpaper {} on {output}", path.display());
+    if video::is_video(path) {
+        println!("video wallpapers are never cached");
+        return Ok(());
+    }
+    for is_dark in [true, false] {
+        let result_key = result_key(path, is_dark);
+        let kmeans_key = kmeans_key(path);
+        let result_hit =
+            bg_config.save_results && state_config.get::<BgResult>(&result_key).is_ok();
+        let kmeans_hit = state_config.get::<KmeanState>(&kmeans_key).is_ok_and(|k| k.is_valid());
+
+        println!("{}:", mode(is_dark).trim_end());
+        if bg_config.save_results {
+            println!("  result key  {result_key}: {}", hit(result_hit));
+        } else {
+            println!("  result key  {result_key}: ignored, save_results is disabled");
+        }
+        println!("  kmeans key  {kmeans_key}: {}", hit(kmeans_hit));
+        let outcome = if result_hit {
+            "the saved result is applied"
+        } else if kmeans_hit {
+            "the theme is generated from the saved centroids"
+        } else {
+            "the wallpaper is decoded and clustered"
+        };
+        println!("  {outcome}");
+    }
+    Ok(())
+}
+
+fn hit(hit: bool) -> &'static str {
+    if hit {
+        "hit"
+    } else {
+        "miss"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_follow_symlink_target() {
+        let dir = std::env::temp_dir().join(format!("{}-symlink-{}", ID, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, link) = (dir.join("a.png"), dir.join("b.png"), dir.join("current.png"));
+        std::fs::write(&a, b"").unwrap();
+        std::fs::write(&b, b"").unwrap();
+
+        std::os::unix::fs::symlink(&a, &link).unwrap();
+        let first = result_key(&link, true);
+        std::fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink(&b, &link).unwrap();
+        let second = result_key(&link, true);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_ne!(first, second);
+        assert!(first.ends_with("_a.png_true"));
+        assert!(second.ends_with("_b.png_true"));
+    }
+}
@@ -0,0 +1,177 @@
+//! Command line handling
+
+use std::path::PathBuf;
+
+const USAGE: &str = "Usage: cosmic-ext-bg-theme [OPTION]
+
+Without options, runs the theming daemon.
+
+Options:
+  --json-events               Run the daemon and report its lifecycle events
+                              as JSON lines on stderr
+  --export-theme-json <path>  Write the themes generated for the current
+                              wallpaper as JSON and exit
+  --export-vim <path>         Write the themes generated for the current
+                              wallpaper as a Vim colorscheme and exit
+  --list-cache                List the cached results and k-means centroids
+  --explain                   Show which caches would be used for the current
+                              wallpaper, without applying anything
+  --import-avoid <file> (--accents|--bg) (--dark|--light)
+                              Append the colors of a palette file to an avoid
+                              list. The file holds one hex color per line, or
+                              is a GIMP .gpl palette
+  --test-avoid <color>        Show whether avoiding a hex color changes the
+                              accent or background of the current wallpaper
+  --apply-hex <accent> [--bg <color>] [--neutral <color>] [--dark|--light]
+                              Apply hex colors as the theme of both modes, or
+                              of one, without looking at the wallpaper
+  --backup <file>             Write the config and the cached results and
+                              centroids to a file
+  --restore <file>            Restore the config and the caches from a backup
+  --contrast-report [--apca]  Print the contrast of the accent, text and
+                              neutral on the background of the written
+                              themes, also in APCA with --apca
+  --benchmark --image <file> [--runs <n>]
+                              Time the decode, resize and k-means stages of
+                              the extraction over n runs (10 by default)
+  --doctor                    Check the session bus, the settings daemon, the
+                              wallpaper and the configs, and report problems
+  -h, --help                  Show this help and exit";
+
+/// Number of runs of `--benchmark` without `--runs`
+const DEFAULT_BENCHMARK_RUNS: usize = 10;
+
+/// What the process was asked to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run the theming daemon, optionally reporting its lifecycle events as
+    /// JSON lines on stderr
+    Daemon { json_events: bool },
+    /// Print the usage
+    Help,
+    /// Write the full dark and light themes for the current wallpaper as JSON
+    ExportThemeJson(PathBuf),
+    /// Write the dark and light themes for the current wallpaper as a Vim
+    /// colorscheme
+    ExportVim(PathBuf),
+    /// List the entries of the state config
+    ListCache,
+    /// Report the cache keys and hits for the current wallpaper
+    Explain,
+    /// Append the colors of a palette file to one of the avoid lists
+    ImportAvoid { file: PathBuf, accents: bool, is_dark: bool },
+    /// Compare the themes generated with and without avoiding a color
+    TestAvoid(String),
+    /// Apply the given colors without extracting them from the wallpaper, to
+    /// one mode or to both
+    ApplyHex { accent: String, bg: Option<String>, neutral: Option<String>, is_dark: Option<bool> },
+    /// Write the config and the caches to a file
+    Backup(PathBuf),
+    /// Restore the config and the caches from a backup
+    Restore(PathBuf),
+    /// Audit the contrast of the written themes, optionally also in APCA
+    ContrastReport { apca: bool },
+    /// Time the extraction stages on an image
+    Benchmark { image: PathBuf, runs: usize },
+    /// Check everything the daemon depends on
+    Doctor,
+}
+
+impl Command {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let Some(arg) = args.next() else {
+            return Ok(Command::Daemon { json_events: false });
+        };
+        let command = match arg.as_str() {
+            "-h" | "--help" => Command::Help,
+            "--json-events" => Command::Daemon { json_events: true },
+            "--export-theme-json" => Command::ExportThemeJson(value(&mut args, &arg)?.into()),
+            "--export-vim" => Command::ExportVim(value(&mut args, &arg)?.into()),
+            "--list-cache" => Command::ListCache,
+            "--explain" => Command::Explain,
+            "--doctor" => Command::Doctor,
+            "--test-avoid" => Command::TestAvoid(value(&mut args, &arg)?),
+            "--apply-hex" => {
+                let accent = value(&mut args, &arg)?;
+                let mut bg = None;
+                let mut neutral = None;
+                let mut is_dark = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--bg" => bg = Some(value(&mut args, &arg)?),
+                        "--neutral" => neutral = Some(value(&mut args, &arg)?),
+                        "--dark" => is_dark = Some(true),
+                        "--light" => is_dark = Some(false),
+                        _ => anyhow::bail!("Unexpected argument {arg}\n\n{USAGE}"),
+                    }
+                }
+                Command::ApplyHex { accent, bg, neutral, is_dark }
+            },
+            "--backup" => Command::Backup(value(&mut args, &arg)?.into()),
+            "--restore" => Command::Restore(value(&mut args, &arg)?.into()),
+            "--contrast-report" => {
+                let mut apca = false;
+                for arg in args.by_ref() {
+                    match arg.as_str() {
+                        "--apca" => apca = true,
+                        _ => anyhow::bail!("Unexpected argument {arg}\n\n{USAGE}"),
+                    }
+                }
+                Command::ContrastReport { apca }
+            },
+            "--import-avoid" => {
+                let file = value(&mut args, &arg)?.into();
+                let mut accents = None;
+                let mut is_dark = None;
+                for arg in args.by_ref() {
+                    match arg.as_str() {
+                        "--accents" => accents = Some(true),
+                        "--bg" => accents = Some(false),
+                        "--dark" => is_dark = Some(true),
+                        "--light" => is_dark = Some(false),
+                        _ => anyhow::bail!("Unexpected argument {arg}\n\n{USAGE}"),
+                    }
+                }
+                let (Some(accents), Some(is_dark)) = (accents, is_dark) else {
+                    anyhow::bail!(
+                        "--import-avoid requires --accents or --bg and --dark or \
+                         --light\n\n{USAGE}"
+                    );
+                };
+                Command::ImportAvoid { file, accents, is_dark }
+            },
+            "--benchmark" => {
+                let mut image = None;
+                let mut runs = DEFAULT_BENCHMARK_RUNS;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--image" => image = Some(value(&mut args, &arg)?.into()),
+                        "--runs" => {
+                            runs = value(&mut args, &arg)?
+                                .parse()
+                                .map_err(|err| anyhow::anyhow!("Invalid --runs: {err}"))?;
+                        },
+                        _ => anyhow::bail!("Unexpected argument {arg}\n\n{USAGE}"),
+                    }
+                }
+                let Some(image) = image else {
+                    anyhow::bail!("--benchmark requires --image\n\n{USAGE}");
+                };
+                Command::Benchmark { image, runs: runs.max(1) }
+            },
+            _ => anyhow::bail!("Unknown argument {arg}\n\n{USAGE}"),
+        };
+        if let Some(arg) = args.next() {
+            anyhow::bail!("Unexpected argument {arg}\n\n{USAGE}");
+        }
+        Ok(command)
+    }
+
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> anyhow::Result<String> {
+    args.next().ok_or_else(|| anyhow::anyhow!("{flag} requires a value\n\n{USAGE}"))
+}
@@ -0,0 +1,101 @@
+//! Contrast models used to keep the accent readable
+
+use palette::color_difference::Wcag21RelativeContrast;
+use palette::{FromColor, Lch, Srgb, SrgbLuma};
+use serde::{Deserialize, Serialize};
+
+/// How the contrast between two colors is measured
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContrastModel {
+    /// WCAG 2.1 relative contrast ratio, from 1 to 21
+    #[default]
+    Wcag21,
+    /// APCA lightness contrast, the absolute Lc value from 0 to about 108
+    Apca,
+}
+
+impl ContrastModel {
+    /// Contrast of `fg` on `bg`
+    pub fn contrast(self, fg: Lch, bg: Lch) -> f32 {
+        match self {
+            ContrastModel::Wcag21 => {
+                SrgbLuma::from_color(fg).relative_contrast(SrgbLuma::from_color(bg))
+            },
+            ContrastModel::Apca => apca(Srgb::from_color(fg), Srgb::from_color(bg)).abs(),
+        }
+    }
+
+    /// Contrast required for body text
+    pub fn default_min_contrast(self) -> f32 {
+        match self {
+            ContrastModel::Wcag21 => 4.5,
+            ContrastModel::Apca => 60.,
+        }
+    }
+
+    /// Contrast below which a color is considered unreadable
+    pub fn default_min_usable_contrast(self) -> f32 {
+        match self {
+            ContrastModel::Wcag21 => 3.,
+            ContrastModel::Apca => 45.,
+        }
+    }
+}
+
+/// APCA-W3 0.0.98G lightness contrast of `fg` on `bg`, positive for dark text
+/// on a light background and negative for light text on a dark background.
+pub fn apca(fg: Srgb, bg: Srgb) -> f32 {
+    let y_fg = apca_luminance(fg);
+    let y_bg = apca_luminance(bg);
+    if (y_bg - y_fg).abs() < 0.0005 {
+        return 0.;
+    }
+
+    let lc = if y_bg > y_fg {
+        let s = (y_bg.powf(0.56) - y_fg.powf(0.57)) * 1.14;
+        if s < 0.1 {
+            0.
+        } else {
+            s - 0.027
+        }
+    } else {
+        let s = (y_bg.powf(0.65) - y_fg.powf(0.62)) * 1.14;
+        if s > -0.1 {
+            0.
+        } else {
+            s + 0.027
+        }
+    };
+    lc * 100.
+}
+
+/// Screen luminance estimate, with the soft clamp of near-black colors
+fn apca_luminance(c: Srgb) -> f32 {
+    let y = 0.2126729 * c.red.max(0.).powf(2.4)
+        + 0.7151522 * c.green.max(0.).powf(2.4)
+        + 0.072175 * c.blue.max(0.).powf(2.4);
+    if y < 0.022 {
+        y + (0.022 - y).powf(1.414)
+    } else {
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apca_reference_values() {
+        let black = Srgb::new(0., 0., 0.);
+        let white = Srgb::new(1., 1., 1.);
+        assert!((apca(black, white) - 106.04).abs() < 0.1);
+        assert!((apca(white, black) + 107.88).abs() < 0.1);
+    }
+
+    #[test]
+    fn apca_same_color_has_no_contrast() {
+        let grey = Srgb::new(0.5, 0.5, 0.5);
+        assert_eq!(apca(grey, grey), 0.);
+    }
+}
@@ -0,0 +1,103 @@
+//! Audit of the contrast of the written themes
+
+use cosmic_config::CosmicConfigEntry;
+use cosmic_theme::Theme;
+use palette::{FromColor, Lch, Srgba};
+
+use crate::contrast::ContrastModel;
+use crate::{load_config, MyConfig};
+
+/// Two colors of a theme that are shown on top of each other
+struct Pair {
+    name: &'static str,
+    fg: Srgba,
+    bg: Srgba,
+    /// Only needs to be distinguishable, not readable as text
+    usable: bool,
+}
+
+/// Prints the WCAG 2.1 contrast, and with `apca` or an APCA `contrast_model`
+/// also the APCA contrast, of the key color pairs of the dark and light
+/// themes as written. Fails if any pair is below its threshold.
+pub fn run(apca: bool) -> anyhow::Result<()> {
+    let bg_config = load_config();
+    let mut models = vec![ContrastModel::Wcag21];
+    if apca || bg_config.contrast_model == ContrastModel::Apca {
+        models.push(ContrastModel::Apca);
+    }
+
+    let mut low = 0;
+    for is_dark in [true, false] {
+        let mode = if is_dark { "dark" } else { "light" };
+        let config = if is_dark { Theme::dark_config()? } else { Theme::light_config()? };
+        let theme = match Theme::get_entry(&config) {
+            Ok(theme) => theme,
+            Err((errs, theme)) => {
+                for err in errs {
+                    tracing::error!("Failed to get the {} theme: {}", mode, err);
+                }
+                theme
+            },
+        };
+
+        println!("{mode} theme");
+        for pair in pairs(&theme) {
+            print!("  {:<16}", pair.name);
+            for &model in &models {
+                let (contrast, min) = measure(&pair, model, &bg_config);
+                let is_low = contrast < min;
+                low += usize::from(is_low);
+                let model = format!("{model:?}");
+                let flag = if is_low { "low" } else { "" };
+                print!(" {model:>8} {contrast:>6.2} (min {min:>5.2}) {flag:<3}");
+            }
+            println!();
+        }
+    }
+
+    if low > 0 {
+        anyhow::bail!("{low} contrasts are below their threshold");
+    }
+    Ok(())
+}
+
+fn pairs(theme: &Theme) -> [Pair; 3] {
+    let bg = theme.background.base;
+    [
+        Pair { name: "accent on bg", fg: theme.accent.base, bg, usable: false },
+        Pair { name: "text on bg", fg: theme.background.on, bg, usable: false },
+        Pair { name: "neutral on bg", fg: theme.palette.neutral_5, bg, usable: true },
+    ]
+}
+
+/// The contrast of the pair in `model` and the minimum it should reach, from
+/// the config for the configured model and the model's defaults otherwise.
+fn measure(pair: &Pair, model: ContrastModel, bg_config: &MyConfig) -> (f32, f32) {
+    let contrast = model.contrast(Lch::from_color(pair.fg.color), Lch::from_color(pair.bg.color));
+    let min = match (model == bg_config.contrast_model, pair.usable) {
+        (true, false) => bg_config.min_contrast(),
+        (true, true) => bg_config.min_usable_contrast(),
+        (false, false) => model.default_min_contrast(),
+        (false, true) => model.default_min_usable_contrast(),
+    };
+    (contrast, min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds_follow_the_config_model() {
+        let grey = Srgba::new(0.6, 0.6, 0.6, 1.);
+        let pair =
+            Pair { name: "text on bg", fg: grey, bg: Srgba::new(1., 1., 1., 1.), usable: false };
+        let bg_config = MyConfig { min_contrast: Some(2.), ..Default::default() };
+        let (contrast, min) = measure(&pair, ContrastModel::Wcag21, &bg_config);
+        assert_eq!(min, 2.);
+        assert!(contrast > min);
+        let (contrast, min) = measure(&pair, ContrastModel::Apca, &bg_config);
+        assert_eq!(min, ContrastModel::Apca.default_min_contrast());
+        assert!(contrast < min);
+    }
+}
@@ -0,0 +1,164 @@
+//! Self-test of everything the daemon depends on
+
+use std::path::{Path, PathBuf};
+
+use cosmic_bg_config::state::State;
+use cosmic_config::CosmicConfigEntry;
+use cosmic_theme::{Theme, ThemeBuilder};
+
+use crate::{
+    changed_wallpapers, connect_settings_daemon, icc, is_missing_key, load_config, load_config_for,
+    load_conn, load_state, svg, system_config, video, MyConfig, ID, SYSTEM_CONFIG_PATH,
+};
+
+/// Runs each check, printing a pass or fail line for it and a summary. Fails
+/// if any check failed.
+pub async fn run() -> anyhow::Result<()> {
+    let mut results = Vec::new();
+    results.push(report("session bus", check_session_bus().await));
+    results.push(report("settings daemon", check_settings_daemon().await));
+    results.push(report("wallpaper", check_wallpaper()));
+    for is_dark in [true, false] {
+        let name = if is_dark { "dark theme config" } else { "light theme config" };
+        results.push(report(name, check_theme_config(is_dark)));
+    }
+    results.push(report("config", check_config()));
+
+    let failed = results.iter().filter(|passed| !**passed).count();
+    println!();
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} checks failed", results.len());
+    }
+    println!("All {} checks passed", results.len());
+    Ok(())
+}
+
+fn report(name: &str, res: anyhow::Result<String>) -> bool {
+    match res {
+        Ok(detail) => {
+            println!("pass  {name:<20} {detail}");
+            true
+        },
+        Err(err) => {
+            println!("FAIL  {name:<20} {err}");
+            false
+        },
+    }
+}
+
+async fn check_session_bus() -> anyhow::Result<String> {
+    let conn = load_conn().await?;
+    Ok(format!("connected as {}", conn.unique_name().map_or("?", |n| n.as_str())))
+}
+
+async fn check_settings_daemon() -> anyhow::Result<String> {
+    let proxy = connect_settings_daemon().await?;
+    let proxy = proxy.as_ref();
+    let dbus = zbus::fdo::DBusProxy::new(proxy.connection()).await?;
+    if !dbus.name_has_owner(proxy.destination().clone()).await? {
+        anyhow::bail!("{} is not running", proxy.destination());
+    }
+    Ok(format!("{} is running", proxy.destination()))
+}
+
+/// Decodes the wallpaper the daemon themes from, the way the daemon would.
+fn check_wallpaper() -> anyhow::Result<String> {
+    let state = load_state(&State::state()?);
+    let primary_output = load_config().primary_output;
+    let Some((output, source)) =
+        changed_wallpapers(None, &state, primary_output.as_deref()).into_iter().next()
+    else {
+        anyhow::bail!("no wallpaper is set");
+    };
+    let cosmic_bg_config::Source::Path(path) = source else {
+        anyhow::bail!("the wallpaper of {output} is not an image");
+    };
+    let img = decode(path, &load_config_for(source))?;
+    Ok(format!("{} ({}x{}) on {output}", path.display(), img.width(), img.height()))
+}
+
+fn decode(path: &Path, bg_config: &MyConfig) -> anyhow::Result<image::DynamicImage> {
+    if video::is_video(path) {
+        video::grab_frame(path, bg_config.video_frame_cmd.as_deref())
+    } else if svg::is_svg(path) {
+        svg::rasterize(path, bg_config.sample_width)
+    } else {
+        icc::decode(path, bg_config.use_icc_profile)
+    }
+}
+
+/// Reads the theme builder and the theme of the mode, and checks that their
+/// directories aren't read-only, without writing anything.
+///
+/// The daemon watches the theme builders, so a test write would make it apply
+/// the theme again.
+fn check_theme_config(is_dark: bool) -> anyhow::Result<String> {
+    let (builder_id, theme_id) = if is_dark {
+        (cosmic_theme::DARK_THEME_BUILDER_ID, cosmic_theme::DARK_THEME_ID)
+    } else {
+        (cosmic_theme::LIGHT_THEME_BUILDER_ID, cosmic_theme::LIGHT_THEME_ID)
+    };
+    let builder_config =
+        if is_dark { ThemeBuilder::dark_config()? } else { ThemeBuilder::light_config()? };
+    let theme_config = if is_dark { Theme::dark_config()? } else { Theme::light_config()? };
+    let mut errs = Vec::new();
+    if let Err((entry_errs, _)) = ThemeBuilder::get_entry(&builder_config) {
+        errs.extend(entry_errs.into_iter().filter(|err| !is_missing_key(err)));
+    }
+    if let Err((entry_errs, _)) = Theme::get_entry(&theme_config) {
+        errs.extend(entry_errs.into_iter().filter(|err| !is_missing_key(err)));
+    }
+    if !errs.is_empty() {
+        let errs: Vec<String> = errs.iter().map(|err| err.to_string()).collect();
+        anyhow::bail!(
+            "{}; resetting the theme in COSMIC Settings > Appearance rewrites it",
+            errs.join("; ")
+        );
+    }
+
+    for (id, version) in [(builder_id, ThemeBuilder::VERSION), (theme_id, Theme::VERSION)] {
+        let Some(dir) = cosmic_config_dir().map(|d| d.join(format!("cosmic/{id}/v{version}")))
+        else {
+            continue;
+        };
+        let read_only = std::fs::metadata(&dir).is_ok_and(|m| m.permissions().readonly());
+        if read_only {
+            let dir = dir.display();
+            anyhow::bail!("{dir} is read-only, run `chmod -R u+w {dir}` to fix it");
+        }
+    }
+    Ok("readable, not read-only".into())
+}
+
+/// The user's config directory, where cosmic-config keeps the user configs
+fn cosmic_config_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
+/// Loads the config layers like the daemon, collecting the errors instead of
+/// logging them.
+fn check_config() -> anyhow::Result<String> {
+    let mut errs = Vec::new();
+    let mut config = match std::fs::read_to_string(SYSTEM_CONFIG_PATH) {
+        Ok(s) => {
+            let (config, system_errs) = system_config::parse(&s);
+            errs.extend(system_errs.into_iter().map(|err| format!("{SYSTEM_CONFIG_PATH}: {err}")));
+            config
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => MyConfig::default(),
+        Err(err) => {
+            errs.push(format!("{SYSTEM_CONFIG_PATH}: {err}"));
+            MyConfig::default()
+        },
+    };
+    let user_config = cosmic_config::Config::new(ID, MyConfig::VERSION)?;
+    let (user_errs, _) = config.update_keys(&user_config, &MyConfig::keys());
+    errs.extend(user_errs.into_iter().filter(|err| !is_missing_key(err)).map(|e| e.to_string()));
+
+    if !errs.is_empty() {
+        anyhow::bail!("{}", errs.join("; "));
+    }
+    Ok("parsed without errors".into())
+}
@@ -0,0 +1,66 @@
+//! Sampling the colors along the edges of a wallpaper
+
+use palette::Lab;
+
+/// Fraction of the pixels, with the strongest gradients, kept as edges
+const EDGE_FRACTION: f32 = 0.2;
+/// Fewest edge pixels worth clustering
+const MIN_EDGE_PIXELS: usize = 32;
+
+/// The sampled pixels, `width` per row, on the strongest edges of the image,
+/// found with a Sobel filter on the lightness.
+///
+/// The outlines and highlights of a subject can characterize a wallpaper
+/// better than its large flat areas. Samples with too few edges, such as
+/// flat images, are returned as is.
+pub fn edge_pixels(pixels: &[Lab], width: usize) -> Vec<Lab> {
+    let height = if width == 0 { 0 } else { pixels.len() / width };
+    if width < 3 || height < 3 {
+        return pixels.to_vec();
+    }
+
+    let l = |x: usize, y: usize| pixels[y * width + x].l;
+    let mut gradients: Vec<(f32, usize)> = Vec::with_capacity((width - 2) * (height - 2));
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let gx = l(x + 1, y - 1) + 2. * l(x + 1, y) + l(x + 1, y + 1)
+                - l(x - 1, y - 1)
+                - 2. * l(x - 1, y)
+                - l(x - 1, y + 1);
+            let gy = l(x - 1, y + 1) + 2. * l(x, y + 1) + l(x + 1, y + 1)
+                - l(x - 1, y - 1)
+                - 2. * l(x, y - 1)
+                - l(x + 1, y - 1);
+            let magnitude = gx * gx + gy * gy;
+            if magnitude > 0. {
+                gradients.push((magnitude, y * width + x));
+            }
+        }
+    }
+
+    let keep = ((pixels.len() as f32 * EDGE_FRACTION) as usize).min(gradients.len());
+    if keep < MIN_EDGE_PIXELS {
+        return pixels.to_vec();
+    }
+    gradients.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+    gradients[..keep].iter().map(|(_, i)| pixels[*i]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_boundary() {
+        // black on the left, white on the right
+        let width = 32;
+        let pixels: Vec<Lab> = (0..width * width)
+            .map(|i| if i % width < 16 { Lab::new(0., 0., 0.) } else { Lab::new(100., 0., 0.) })
+            .collect();
+        // the two columns on each side of the boundary, without the border rows
+        assert_eq!(edge_pixels(&pixels, width).len(), 2 * (width - 2));
+
+        let flat = vec![Lab::new(50., 0., 0.); width * width];
+        assert_eq!(edge_pixels(&flat, width).len(), flat.len());
+    }
+}
@@ -0,0 +1,62 @@
+//! Machine-readable lifecycle events for front-ends supervising the daemon
+
+use std::fmt;
+
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Writes the lifecycle events as JSON lines on stderr.
+///
+/// Lifecycle events are the tracing events with an `event` field naming them:
+/// `started`, `wallpaper-changed`, `extraction-started`, `extraction-finished`
+/// and `theme-written`. Every error is reported as an `error` event. Each line
+/// holds the `event` name, the `message` and the other fields of the event.
+pub struct JsonEvents;
+
+impl<S: Subscriber> Layer<S> for JsonEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = JsonFields(Map::new());
+        event.record(&mut fields);
+        let mut fields = fields.0;
+        let name = match fields.remove("event") {
+            Some(Value::String(name)) => name,
+            _ if *event.metadata().level() == Level::ERROR => "error".to_string(),
+            _ => return,
+        };
+
+        let mut line = Map::new();
+        line.insert("event".to_string(), Value::String(name));
+        line.extend(fields);
+        eprintln!("{}", Value::Object(line));
+    }
+}
+
+struct JsonFields(Map<String, Value>);
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
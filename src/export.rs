@@ -0,0 +1,194 @@
+//! Exports of the generated themes for external tooling
+
+use std::fmt::Write;
+use std::path::Path;
+
+use cosmic_bg_config::state::State;
+use cosmic_theme::Theme;
+use palette::Srgba;
+use serde::Serialize;
+
+use crate::{apply_output, changed_wallpapers, hex, load_config, load_state};
+
+#[derive(Debug, Serialize)]
+struct ExportedThemes {
+    dark: Theme,
+    light: Theme,
+}
+
+/// Generates the dark and light themes for the current wallpaper without
+/// applying them, and writes them to `path` as JSON.
+pub fn theme_json(path: &Path) -> anyhow::Result<()> {
+    let themes = current_themes()?;
+    std::fs::write(path, serde_json::to_string_pretty(&themes)?)?;
+    Ok(())
+}
+
+/// Generates the dark and light themes for the current wallpaper without
+/// applying them, and writes them to `path` as a Vim colorscheme that follows
+/// `background`.
+pub fn vim_colorscheme(path: &Path) -> anyhow::Result<()> {
+    let themes = current_themes()?;
+    let mut vim = [
+        "\" Generated by cosmic-ext-bg-theme",
+        "hi clear",
+        "if exists('syntax_on')",
+        "  syntax reset",
+        "endif",
+        "let g:colors_name = 'cosmic-ext-bg-theme'",
+        "",
+        "",
+    ]
+    .join("\n");
+    for (branch, theme) in [("if &background ==# 'dark'", &themes.dark), ("else", &themes.light)] {
+        let _ = writeln!(vim, "{branch}");
+        for (group, fg, bg) in vim_groups(theme) {
+            let _ = write!(vim, "  hi {group}");
+            if let Some(fg) = fg {
+                let _ = write!(vim, " guifg={}", hex(fg.color));
+            }
+            if let Some(bg) = bg {
+                let _ = write!(vim, " guibg={}", hex(bg.color));
+            }
+            vim.push('\n');
+        }
+    }
+    vim.push_str("endif\n");
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, vim)?;
+    Ok(())
+}
+
+/// The highlight groups of the Vim colorscheme, with their foreground and
+/// background
+fn vim_groups(theme: &Theme) -> Vec<(&'static str, Option<Srgba>, Option<Srgba>)> {
+    let p = &theme.palette;
+    let (bg, fg) = (theme.background.base, theme.background.on);
+    let (ui, on_ui) = (theme.primary.base, theme.primary.on);
+    let (accent, on_accent) = (theme.accent.base, theme.accent.on);
+    vec![
+        ("Normal", Some(fg), Some(bg)),
+        ("NormalFloat", Some(on_ui), Some(ui)),
+        ("Pmenu", Some(on_ui), Some(ui)),
+        ("PmenuSel", Some(on_accent), Some(accent)),
+        ("StatusLine", Some(on_ui), Some(ui)),
+        ("StatusLineNC", Some(p.neutral_6), Some(ui)),
+        ("VertSplit", Some(p.neutral_4), Some(bg)),
+        ("WinSeparator", Some(p.neutral_4), Some(bg)),
+        ("CursorLine", None, Some(ui)),
+        ("LineNr", Some(p.neutral_5), None),
+        ("CursorLineNr", Some(accent), None),
+        ("Visual", Some(on_accent), Some(accent)),
+        ("Search", Some(on_accent), Some(accent)),
+        ("MatchParen", Some(accent), None),
+        ("Comment", Some(p.neutral_6), None),
+        ("Identifier", Some(accent), None),
+        ("Function", Some(accent), None),
+        ("Statement", Some(p.accent_blue), None),
+        ("String", Some(p.accent_green), None),
+        ("Constant", Some(p.accent_purple), None),
+        ("Type", Some(p.accent_yellow), None),
+        ("Error", Some(p.accent_red), None),
+        ("ErrorMsg", Some(p.accent_red), None),
+        ("WarningMsg", Some(p.accent_yellow), None),
+    ]
+}
+
+/// Generates the dark and light themes for the current wallpaper, as a dry run
+fn current_themes() -> anyhow::Result<ExportedThemes> {
+    let state = load_state(&State::state()?);
+    let primary = load_config().primary_output;
+    let Some((output, source)) =
+        changed_wallpapers(None, &state, primary.as_deref()).into_iter().next()
+    else {
+        anyhow::bail!("No wallpapers found");
+    };
+
+    let dark = apply_output(output, source, true, None, true)?;
+    let light = apply_output(output, source, false, Some(dark.result.accent), true)?;
+    Ok(ExportedThemes { dark: dark.builder.build(), light: light.builder.build() })
+}
+
+/// Writes the theme colors in pywal's `colors.json` format, mapping the
+/// palette onto the 16 terminal colors.
+pub fn pywal(theme: &Theme, wallpaper: &Path, path: &Path) -> anyhow::Result<()> {
+    let p = &theme.palette;
+    let bg = theme.background.base;
+    let fg = theme.background.on;
+    let colors: [Srgba; 16] = [
+        bg,
+        p.accent_red,
+        p.accent_green,
+        p.accent_yellow,
+        p.accent_blue,
+        p.accent_purple,
+        theme.accent.base,
+        fg,
+        p.neutral_5,
+        p.bright_red,
+        p.bright_green,
+        p.ext_yellow,
+        p.ext_blue,
+        p.ext_pink,
+        p.ext_indigo,
+        p.neutral_9,
+    ];
+
+    let colors: serde_json::Map<String, serde_json::Value> = colors
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (format!("color{i}"), hex(c.color).into()))
+        .collect();
+    let json = serde_json::json!({
+        "wallpaper": wallpaper,
+        "alpha": "100",
+        "special": {
+            "background": hex(bg.color),
+            "foreground": hex(fg.color),
+            "cursor": hex(theme.accent.base.color),
+        },
+        "colors": colors,
+    });
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+    Ok(())
+}
+
+/// Writes the theme colors as GTK named colors, for GTK apps to import from
+/// their `gtk.css`.
+pub fn gtk_css(theme: &Theme, path: &Path) -> anyhow::Result<()> {
+    let p = &theme.palette;
+    let colors: [(&str, Srgba); 15] = [
+        ("accent_color", theme.accent.base),
+        ("accent_bg_color", theme.accent.base),
+        ("accent_fg_color", theme.accent.on),
+        ("window_bg_color", theme.background.base),
+        ("window_fg_color", theme.background.on),
+        ("headerbar_bg_color", theme.background.base),
+        ("headerbar_fg_color", theme.background.on),
+        ("view_bg_color", theme.primary.base),
+        ("view_fg_color", theme.primary.on),
+        ("card_bg_color", theme.primary.base),
+        ("card_fg_color", theme.primary.on),
+        ("popover_bg_color", theme.primary.base),
+        ("popover_fg_color", theme.primary.on),
+        ("success_color", p.accent_green),
+        ("error_color", p.accent_red),
+    ];
+    let css: String = colors
+        .iter()
+        .map(|(name, c)| format!("@define-color {name} {};\n", hex(c.color)))
+        .collect();
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, css)?;
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! Deferring theme changes while a fullscreen window is shown
+
+use std::time::Duration;
+
+use tokio::process::Command;
+
+/// How often the detection command is re-run while a change is deferred
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether theme changes should be held back right now, because
+/// `defer_while_fullscreen` is set and `fullscreen_cmd` reports a fullscreen
+/// window.
+pub async fn should_defer() -> bool {
+    let config = crate::load_config();
+    match config.fullscreen_cmd {
+        Some(cmd) if config.defer_while_fullscreen => is_fullscreen(&cmd).await,
+        _ => false,
+    }
+}
+
+/// Completes once changes no longer need to be deferred.
+pub async fn cleared() {
+    while should_defer().await {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Whether a fullscreen window is present, according to `cmd`.
+///
+/// The command is run with `sh -c` and reports a fullscreen window by exiting
+/// successfully. A command that can't be run counts as no fullscreen window, so
+/// that a broken check never blocks theming.
+async fn is_fullscreen(cmd: &str) -> bool {
+    match Command::new("sh").arg("-c").arg(cmd).status().await {
+        Ok(status) => status.success(),
+        Err(err) => {
+            tracing::error!("Failed to run the fullscreen check: {}", err);
+            false
+        },
+    }
+}
@@ -0,0 +1,51 @@
+//! Decoding wallpapers with their embedded color profile
+
+use std::path::Path;
+
+use image::{DynamicImage, ImageDecoder};
+
+/// Decodes the image.
+///
+/// With `use_profile` and the `icc` feature, an image with an embedded ICC
+/// profile is converted to sRGB. Images without a profile, or whose profile
+/// can't be applied, are assumed to be sRGB.
+pub fn decode(path: &Path, use_profile: bool) -> anyhow::Result<DynamicImage> {
+    let mut decoder = image::io::Reader::open(path)?.with_guessed_format()?.into_decoder()?;
+    let profile = if use_profile && cfg!(feature = "icc") { decoder.icc_profile()? } else { None };
+    let img = DynamicImage::from_decoder(decoder)?;
+    let Some(profile) = profile else {
+        return Ok(img);
+    };
+    match to_srgb(&img, &profile) {
+        Ok(converted) => Ok(converted),
+        Err(err) => {
+            tracing::error!("Failed to apply the color profile of {}: {}", path.display(), err);
+            Ok(img)
+        },
+    }
+}
+
+#[cfg(feature = "icc")]
+fn to_srgb(img: &DynamicImage, profile: &[u8]) -> anyhow::Result<DynamicImage> {
+    use lcms2::{Intent, PixelFormat, Profile, Transform};
+
+    let transform = Transform::new(
+        &Profile::new_icc(profile)?,
+        PixelFormat::RGB_8,
+        &Profile::new_srgb(),
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )?;
+    let img = img.to_rgb8();
+    let (width, height) = img.dimensions();
+    let mut pixels: Vec<[u8; 3]> = img.pixels().map(|p| p.0).collect();
+    transform.transform_in_place(&mut pixels);
+    let img = image::RgbImage::from_raw(width, height, pixels.concat())
+        .ok_or_else(|| anyhow::anyhow!("The converted image has the wrong size"))?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+#[cfg(not(feature = "icc"))]
+fn to_srgb(img: &DynamicImage, _profile: &[u8]) -> anyhow::Result<DynamicImage> {
+    Ok(img.clone())
+}
@@ -0,0 +1,23 @@
+//! The wallpapers cosmic-bg last saved, read right before applying
+
+use cosmic_bg_config::state::State;
+
+/// The state as cosmic-bg last saved it, if its wallpapers differ from those of
+/// `state`.
+///
+/// cosmic-bg saves the image of each output to its state config when it shows
+/// it. The state held by the daemon only follows the config through the change
+/// signals of the settings daemon, which can lag behind during a rotation and
+/// are missed while reconnecting. Returns `None` if the config can't be read,
+/// holds no wallpapers or matches `state`, so that `state` is used.
+pub fn resolve(state: &State) -> Option<State> {
+    let config = match State::state() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::debug!("The saved wallpapers are unavailable: {}", err);
+            return None;
+        },
+    };
+    let saved = crate::load_state(&config);
+    (!saved.wallpapers.is_empty() && saved.wallpapers != state.wallpapers).then_some(saved)
+}
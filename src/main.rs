@@ -1,87 +1,196 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use cosmic_bg_config::state::State;
 use cosmic_config::cosmic_config_derive::CosmicConfigEntry;
 use cosmic_config::{Config, ConfigGet, ConfigSet, CosmicConfigEntry};
 use cosmic_settings_daemon::{ConfigProxy, CosmicSettingsDaemonProxy};
-use cosmic_theme::{Theme, ThemeBuilder};
+use cosmic_theme::{Theme, ThemeBuilder, ThemeMode};
 use fast_image_resize::images::Image;
-use fast_image_resize::{IntoImageView, Resizer};
+use fast_image_resize::{PixelType, Resizer};
 use futures::StreamExt;
-use kmeans_colors::{get_kmeans, Kmeans, Sort};
-use palette::color_difference::Wcag21RelativeContrast;
-use palette::{Clamp, FromColor, IntoColor, Lab, Lch, Saturate, Srgb, SrgbLuma, Srgba};
+use image::DynamicImage;
+use kmeans_colors::{get_kmeans, CentroidData, Kmeans, Sort};
+use palette::color_difference::Ciede2000;
+use palette::{
+    Clamp, FromColor, FromColorUnclamped, IntoColor, IsWithinBounds, Lab, LabHue, Lch, Saturate,
+    Srgb, Srgba,
+};
+use rand::seq::SliceRandom;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{fmt, EnvFilter};
 use zbus::Connection;
 
+use crate::avoid::AvoidColor;
+use crate::cli::Command;
+use crate::contrast::ContrastModel;
+use crate::profiles::DirectoryProfile;
+
+mod animation;
+mod avoid;
+mod backup;
+mod benchmark;
+mod cache;
+mod cli;
+mod contrast;
+mod contrast_report;
+mod doctor;
+mod edges;
+mod events;
+mod export;
+mod fullscreen;
+mod icc;
+mod live;
+mod manual;
+mod names;
+mod portal;
+mod profiles;
+mod region;
+mod remote;
+mod rotate;
+mod saliency;
+mod svg;
+mod system_config;
+mod thumbnail;
+mod video;
+
 const ID: &str = "gay.ash.CosmicExtBgTheme";
+const SYSTEM_CONFIG_PATH: &str = "/etc/cosmic-ext-bg-theme/config.ron";
+const DBUS_PATH: &str = "/gay/ash/CosmicExtBgTheme";
+const DEFAULT_CLUSTERS: usize = 8;
+const AUTO_CLUSTERS: std::ops::RangeInclusive<usize> = 2..=12;
+const LAST_CLUSTERS_KEY: &str = "last_clusters";
+/// The coarsest lightness grid allowed for the contrast search
+const MIN_CONTRAST_SEARCH_STEPS: usize = 4;
+/// Score multiplier for accent candidates in the preferred hue band
+const HUE_PREFERENCE_BONUS: f32 = 1.5;
+/// Score multiplier for accent candidates near the hue of the other mode
+const CONSISTENT_HUE_BONUS: f32 = 1.5;
+/// Hue difference in degrees within which a candidate gets the consistent hue
+/// bonus
+const CONSISTENT_HUE_RANGE: f32 = 30.;
+/// How long `post_apply_cmd` may run before it is killed
+const POST_APPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before the first retry of a failed config write, growing linearly
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+/// Range `kmeans_max_iter` is clamped to
+const KMEANS_MAX_ITER: std::ops::RangeInclusive<usize> = 1..=1000;
+/// Range `kmeans_convergence` is clamped to
+const KMEANS_CONVERGENCE: std::ops::RangeInclusive<f32> = 0.001..=100.;
+/// Fewest centroids `max_candidates` can keep, one each for the background,
+/// the accent and the text
+const MIN_CANDIDATES: usize = 3;
+/// Fractions of the accent lightness bias tried, from the full bias down
+const LIGHTNESS_BIAS_STEPS: usize = 10;
+/// Bisection steps of the search for the highest in-gamut chroma
+const GAMUT_SEARCH_STEPS: usize = 16;
+/// Range of `bg_chroma_delta` over `intensity`
+const INTENSITY_BG_CHROMA_DELTA: std::ops::RangeInclusive<f32> = 5.0..=25.;
+/// Range of `neutral_max_chroma` over `intensity`
+const INTENSITY_NEUTRAL_MAX_CHROMA: std::ops::RangeInclusive<f32> = 10.0..=30.;
+/// `accent_min_final_chroma` at full `intensity`
+const INTENSITY_ACCENT_MIN_CHROMA: f32 = 60.;
+/// Shortest wait before reconnecting to the settings daemon
+const RECONNECT_COOLDOWN: Duration = Duration::from_secs(1);
+/// Window in which reconnects count as rapid
+const RAPID_RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+/// Rapid reconnects tolerated before backing off for `RAPID_RECONNECT_BACKOFF`
+const MAX_RAPID_RECONNECTS: usize = 5;
+/// Wait before reconnecting while the settings daemon keeps restarting
+const RAPID_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let command = Command::parse(std::env::args().skip(1))?;
+
     let fmt_layer = fmt::layer().with_target(false);
     let filter_layer =
         EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")).unwrap();
+    let events_layer =
+        matches!(command, Command::Daemon { json_events: true }).then_some(events::JsonEvents);
     if let Ok(journal_layer) = tracing_journald::layer() {
-        tracing_subscriber::registry().with(journal_layer).with(filter_layer).init();
+        tracing_subscriber::registry()
+            .with(journal_layer)
+            .with(events_layer)
+            .with(filter_layer)
+            .init();
     } else {
-        tracing_subscriber::registry().with(fmt_layer).with(filter_layer).init();
+        tracing_subscriber::registry().with(fmt_layer).with(events_layer).with(filter_layer).init();
     }
 
     log_panics::init();
-    tracing::info!("Starting CosmicExtBgTheme");
+
+    match command {
+        Command::Daemon { .. } => {},
+        Command::Help => {
+            println!("{}", Command::usage());
+            return Ok(());
+        },
+        Command::ExportThemeJson(path) => return export::theme_json(&path),
+        Command::ExportVim(path) => return export::vim_colorscheme(&path),
+        Command::ListCache => return cache::list(),
+        Command::Explain => return cache::explain(),
+        Command::ImportAvoid { file, accents, is_dark } => {
+            return avoid::import(&file, accents, is_dark);
+        },
+        Command::Benchmark { image, runs } => return benchmark::run(&image, runs),
+        Command::Doctor => return doctor::run().await,
+        Command::TestAvoid(color) => return avoid::test(&color),
+        Command::ApplyHex { accent, bg, neutral, is_dark } => {
+            let modes = is_dark.map_or(vec![true, false], |is_dark| vec![is_dark]);
+            return manual::apply(&accent, bg.as_deref(), neutral.as_deref(), &modes);
+        },
+        Command::Backup(file) => return backup::backup(&file),
+        Command::Restore(file) => return backup::restore(&file),
+        Command::ContrastReport { apca } => return contrast_report::run(apca),
+    }
+
+    tracing::info!(event = "started", "Starting CosmicExtBgTheme");
     let settings_proxy = connect_settings_daemon().await?;
     let config = State::state()?;
-    let (path, name) = settings_proxy.watch_state(cosmic_bg_config::NAME, State::version()).await?;
-    let bg_state_proxy = ConfigProxy::builder(settings_proxy.as_ref().connection())
-        .path(path)?
-        .destination(name)?
-        .build()
-        .await?;
     let config_context = cosmic_bg_config::context()?;
 
-    let mut state = match State::get_entry(&config) {
-        Ok(entry) => entry,
-        Err((errs, entry)) => {
-            for err in errs {
-                tracing::error!("Failed to get the current state: {}", err);
-            }
-            entry
-        },
-    };
+    let mut state = load_state(&config);
     let mut prev_state = None;
 
-    if let Err(err) = apply_state(prev_state.as_ref(), &state, true) {
-        tracing::error!("Failed to apply the state: {}", err);
+    let conn = settings_proxy.as_ref().connection().clone();
+    if let Err(err) = conn.request_name(ID).await {
+        tracing::warn!("Failed to request the bus name {}: {}", ID, err);
+    }
+
+    if load_config().apply_last_on_startup {
+        apply_last_results(&conn).await;
     }
 
-    if let Err(err) = apply_state(prev_state.as_ref(), &state, false) {
-        tracing::error!("Failed to apply the state: {}", err);
+    // give the desktop a moment to settle after login before the first apply
+    let startup_delay = Duration::from_millis(load_config().startup_delay_ms);
+    if !startup_delay.is_zero() {
+        tokio::time::sleep(startup_delay).await;
+        state = load_state(&config);
     }
 
+    apply_modes(&conn, prev_state.as_ref(), &state).await;
+
     prev_state = Some(state.clone());
 
     let mut fail_count = 0;
+    // when the recent reconnects happened, to back off while the settings
+    // daemon is in a restart loop
+    let mut reconnects: Vec<Instant> = Vec::new();
     loop {
-        fail_count = match run(
-            &mut prev_state,
-            fail_count,
-            &bg_state_proxy,
-            &settings_proxy,
-            &mut state,
-            &config,
-        )
-        .await
-        {
-            Ok(fail_count) => fail_count,
-            Err(err) => {
-                tracing::error!("Failed to run the main loop: {}", err);
-                fail_count += 1;
-                fail_count
-            },
-        };
+        fail_count =
+            match run(&mut prev_state, fail_count, &settings_proxy, &mut state, &config).await {
+                Ok(fail_count) => fail_count,
+                Err(err) => {
+                    tracing::error!("Failed to run the main loop: {}", err);
+                    fail_count += 1;
+                    fail_count
+                },
+            };
 
         let config_dur =
             cosmic_bg_config::Config::load(&config_context).map_or(Duration::MAX, |c| {
@@ -89,34 +198,109 @@ async fn main() -> anyhow::Result<()> {
                     .first()
                     .map_or(Duration::MAX, |b| Duration::from_secs(b.rotation_frequency))
             });
-        let sleep = Duration::from_secs(2_u64.saturating_pow(fail_count)).min(config_dur);
+        let now = Instant::now();
+        reconnects.retain(|t| now.duration_since(*t) < RAPID_RECONNECT_WINDOW);
+        reconnects.push(now);
+        let sleep = reconnect_delay(fail_count, config_dur, reconnects.len());
         tokio::time::sleep(sleep).await;
     }
 }
 
+/// How long to wait before reconnecting after `fail_count` failures, with
+/// `rapid_reconnects` reconnects in the last `RAPID_RECONNECT_WINDOW`.
+///
+/// The exponential backoff is capped by the wallpaper rotation, so that a
+/// rotation isn't missed for long, but never drops below the cooldown, and a
+/// daemon that keeps restarting gets the longer backoff.
+fn reconnect_delay(fail_count: u32, rotation: Duration, rapid_reconnects: usize) -> Duration {
+    let delay = Duration::from_secs(2_u64.saturating_pow(fail_count)).min(rotation);
+    if rapid_reconnects > MAX_RAPID_RECONNECTS {
+        tracing::warn!(
+            "Reconnected {} times in {:?}, backing off for {:?}",
+            rapid_reconnects,
+            RAPID_RECONNECT_WINDOW,
+            RAPID_RECONNECT_BACKOFF
+        );
+        delay.max(RAPID_RECONNECT_BACKOFF)
+    } else {
+        delay.max(RECONNECT_COOLDOWN)
+    }
+}
+
 async fn run(
     prev_state: &mut Option<State>,
     mut fail_count: u32,
-    bg_state_proxy: &ConfigProxy<'static>,
     settings_proxy: &CosmicSettingsDaemonProxy<'static>,
     state: &mut State,
     config: &Config,
 ) -> anyhow::Result<u32> {
+    // The subscription is tied to the settings daemon instance, so it has to be
+    // re-established whenever the daemon restarts.
+    let bg_state_proxy = watch_bg_state(settings_proxy).await?;
     let mut changes = bg_state_proxy.receive_changed().await?;
 
     let mut ownership_change = settings_proxy.as_ref().receive_owner_changed().await?;
 
+    let dark_builder_proxy = watch_theme_builder(settings_proxy, true).await?;
+    let light_builder_proxy = watch_theme_builder(settings_proxy, false).await?;
+    let mut theme_changes = futures::stream::select(
+        dark_builder_proxy.receive_changed().await?,
+        light_builder_proxy.receive_changed().await?,
+    );
+
+    // The state may have changed while we weren't subscribed
+    let new_state = load_state(config);
+    if prev_state.as_ref().map_or(true, |prev| prev.wallpapers != new_state.wallpapers) {
+        *state = new_state;
+        apply_modes(settings_proxy.as_ref().connection(), prev_state.as_ref(), state).await;
+        *prev_state = Some(state.clone());
+    }
+
+    // whether a change is waiting for a fullscreen window to go away
+    let mut deferred = false;
+    // how many times the accent has been rotated
+    let mut rotation = 0;
+    // created once, so that the other events don't restart the timers
+    let mut video_ticker = Ticker::default();
+    let mut rotate_ticker = Ticker::default();
     loop {
         let c = tokio::select! {
             c = changes.next() => c,
-            c = ownership_change.next() => {
-                if c.is_none() {
-                    // The settings daemon has exited
+            _ = fullscreen::cleared(), if deferred => {
+                deferred = false;
+                apply_modes(settings_proxy.as_ref().connection(), prev_state.as_ref(), state).await;
+                *prev_state = Some(state.clone());
+                continue;
+            },
+            Some(_) = theme_changes.next() => {
+                if !state.wallpapers.is_empty() && theme_was_reset() {
+                    tracing::info!("The theme was reset, applying the wallpaper theme again");
+                    apply_modes(settings_proxy.as_ref().connection(), None, state).await;
+                }
+                continue;
+            },
+            _ = video_ticker.tick(video_refresh_period(state)) => {
+                apply_modes(settings_proxy.as_ref().connection(), None, state).await;
+                continue;
+            },
+            _ = rotate_ticker.tick(rotate::period()) => {
+                rotation += 1;
+                rotate::apply(settings_proxy.as_ref().connection(), rotation).await;
+                continue;
+            },
+            c = ownership_change.next() => match c {
+                Some(Some(_)) => {
+                    tracing::info!("The settings daemon has restarted");
+                    return Ok(0);
+                },
+                Some(None) => {
+                    tracing::warn!("The settings daemon has exited, waiting for it to return");
+                    continue;
+                },
+                None => {
                     tracing::error!("The settings daemon has exited");
                     break;
-                } else {
-                    None
-                }
+                },
             },
         };
         let c = match c {
@@ -136,22 +320,19 @@ async fn run(
         for err in errors {
             tracing::error!("Failed to update the state: {}", err);
         }
+        tracing::info!(event = "wallpaper-changed", "The wallpaper has changed");
 
         fail_count = 0;
 
-        match apply_state(prev_state.as_ref(), &state, true) {
-            Ok(kmeans) => Some(kmeans),
-            Err(err) => {
-                tracing::error!("Failed to apply the state: {}", err);
-                None
-            },
-        };
-        if let Err(err) = apply_state(prev_state.as_ref(), &state, true) {
-            tracing::error!("Failed to apply the state: {}", err);
-        }
-        if let Err(err) = apply_state(prev_state.as_ref(), &state, false) {
-            tracing::error!("Failed to apply the state: {}", err);
+        if fullscreen::should_defer().await {
+            // the previous state is kept, so that everything that changed
+            // meanwhile is applied once the window is gone
+            tracing::info!("Deferring the theme change while a window is fullscreen");
+            deferred = true;
+            continue;
         }
+
+        apply_modes(settings_proxy.as_ref().connection(), prev_state.as_ref(), state).await;
         *prev_state = Some(state.clone());
     }
 
@@ -159,6 +340,180 @@ async fn run(
     Ok(fail_count)
 }
 
+/// How often the wallpaper that drives the theme should be re-sampled, if it
+/// is a video
+fn video_refresh_period(state: &State) -> Option<Duration> {
+    let bg_config = load_config();
+    let refresh_secs = bg_config.video_refresh_secs;
+    // the wallpaper a refresh themes from
+    let themed = changed_wallpapers(None, state, bg_config.primary_output.as_deref());
+    let has_video = themed.first().is_some_and(|(_, source)| {
+        matches!(source, cosmic_bg_config::Source::Path(path) if video::is_video(path))
+    });
+    (cfg!(feature = "video") && has_video && refresh_secs > 0)
+        .then_some(Duration::from_secs(refresh_secs))
+}
+
+/// A periodic timer for a `select!` loop, which keeps its schedule across the
+/// iterations and only restarts when its period changes.
+#[derive(Default)]
+struct Ticker {
+    period: Option<Duration>,
+    interval: Option<tokio::time::Interval>,
+}
+
+impl Ticker {
+    /// Completes at the next tick of `period`, or never without a period.
+    async fn tick(&mut self, period: Option<Duration>) {
+        if self.period != period {
+            self.period = period;
+            self.interval = period.map(|period| {
+                let start = tokio::time::Instant::now() + period;
+                let mut interval = tokio::time::interval_at(start, period);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                interval
+            });
+        }
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            },
+            None => std::future::pending::<()>().await,
+        }
+    }
+}
+
+async fn watch_bg_state(
+    settings_proxy: &CosmicSettingsDaemonProxy<'static>,
+) -> anyhow::Result<ConfigProxy<'static>> {
+    let (path, name) = settings_proxy.watch_state(cosmic_bg_config::NAME, State::version()).await?;
+    let bg_state_proxy = ConfigProxy::builder(settings_proxy.as_ref().connection())
+        .path(path)?
+        .destination(name)?
+        .build()
+        .await?;
+    Ok(bg_state_proxy)
+}
+
+async fn watch_theme_builder(
+    settings_proxy: &CosmicSettingsDaemonProxy<'static>,
+    is_dark: bool,
+) -> anyhow::Result<ConfigProxy<'static>> {
+    let id = if is_dark {
+        cosmic_theme::DARK_THEME_BUILDER_ID
+    } else {
+        cosmic_theme::LIGHT_THEME_BUILDER_ID
+    };
+    let (path, name) = settings_proxy.watch_config(id, ThemeBuilder::VERSION).await?;
+    let proxy = ConfigProxy::builder(settings_proxy.as_ref().connection())
+        .path(path)?
+        .destination(name)?
+        .build()
+        .await?;
+    Ok(proxy)
+}
+
+/// Whether the dark or the light theme was reset to the defaults by another
+/// tool, so that neither the accent nor the background the daemon writes are
+/// set anymore.
+fn theme_was_reset() -> bool {
+    let fields = load_config().write_fields;
+    if !fields.accent && !fields.bg {
+        // the daemon leaves both unset itself
+        return false;
+    }
+    [ThemeBuilder::dark_config(), ThemeBuilder::light_config()].into_iter().any(|config| {
+        config
+            .map(|c| ThemeBuilder::get_entry(&c).unwrap_or_else(|(_, entry)| entry))
+            .is_ok_and(|t| t.accent.is_none() && t.bg_color.is_none())
+    })
+}
+
+/// Loads the config, with the color aliases resolved and the common avoid lists
+/// merged into the lists of each mode.
+fn load_config() -> MyConfig {
+    resolve_config(load_config_layers(user_config().as_ref()), None)
+}
+
+/// The config for the wallpaper `source`, with the overrides of the directory
+/// profiles that contain it.
+fn load_config_for(source: &cosmic_bg_config::Source) -> MyConfig {
+    let wallpaper = match source {
+        cosmic_bg_config::Source::Path(path) => Some(path.as_path()),
+        _ => None,
+    };
+    resolve_config(load_config_layers(user_config().as_ref()), wallpaper)
+}
+
+/// Completes the layered config: merges the directory profiles of `wallpaper`
+/// into it, then resolves the aliases, merges the common avoid lists and
+/// applies the intensity, so that these see the overrides and run once.
+fn resolve_config(layers: MyConfig, wallpaper: Option<&std::path::Path>) -> MyConfig {
+    let mut config = match wallpaper {
+        Some(wallpaper) => profiles::apply(layers, wallpaper),
+        None => layers,
+    };
+    config.resolve_color_aliases();
+    config.merge_common_avoid();
+    config.apply_intensity();
+    config
+}
+
+/// The user config, with one file per key
+fn user_config() -> Option<Config> {
+    Config::new(ID, MyConfig::VERSION).ok()
+}
+
+/// Loads the config in layers. Each key set in `user_config` overrides the
+/// system config at [`SYSTEM_CONFIG_PATH`], which overrides the built-in
+/// defaults. A malformed key keeps the value of the layer below it.
+fn load_config_layers(user_config: Option<&Config>) -> MyConfig {
+    let mut config = match std::fs::read_to_string(SYSTEM_CONFIG_PATH) {
+        Ok(s) => {
+            let (config, errs) = system_config::parse(&s);
+            for err in errs {
+                tracing::error!("Failed to parse {}: {}", SYSTEM_CONFIG_PATH, err);
+            }
+            config
+        },
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::error!("Failed to read {}: {}", SYSTEM_CONFIG_PATH, err);
+            }
+            MyConfig::default()
+        },
+    };
+
+    if let Some(user_config) = user_config {
+        let keys = MyConfig::keys();
+        let (errs, _) = config.update_keys(user_config, &keys);
+        for err in errs.into_iter().filter(|err| !is_missing_key(err)) {
+            tracing::error!("Failed to get the config: {}", err);
+        }
+    }
+    config
+}
+
+fn is_missing_key(err: &cosmic_config::Error) -> bool {
+    match err {
+        cosmic_config::Error::NotFound => true,
+        cosmic_config::Error::GetKey(_, err) => err.kind() == std::io::ErrorKind::NotFound,
+        _ => false,
+    }
+}
+
+fn load_state(config: &Config) -> State {
+    match State::get_entry(config) {
+        Ok(entry) => entry,
+        Err((errs, entry)) => {
+            for err in errs {
+                tracing::error!("Failed to get the current state: {}", err);
+            }
+            entry
+        },
+    }
+}
+
 async fn load_conn() -> anyhow::Result<Connection> {
     for _ in 0..5 {
         match Connection::session().await {
@@ -186,123 +541,465 @@ async fn connect_settings_daemon() -> anyhow::Result<CosmicSettingsDaemonProxy<'
     Err(anyhow::anyhow!("Failed to connect to the settings daemon"))
 }
 
-fn apply_state(prev_state: Option<&State>, state: &State, is_dark: bool) -> anyhow::Result<()> {
-    let changed = prev_state
-        .as_ref()
-        .and_then(|prev| {
-            state.wallpapers.iter().find(|(k, v)| {
-                prev.wallpapers.iter().find(|p| &p.0 == k).map_or(true, |prev_v| prev_v.1 != *v)
-            })
-        })
-        .or_else(|| state.wallpapers.first());
-    let Some(w) = changed else {
+/// Applies the state to both the dark and the light theme.
+///
+/// With `link_modes`, the light theme reuses the accent hue of the dark theme.
+async fn apply_modes(conn: &Connection, prev_state: Option<&State>, state: &State) {
+    if state.wallpapers.is_empty() {
+        // a fresh profile, the state watch reports the first wallpaper
+        tracing::info!("No wallpaper is set yet, waiting for one");
+        return;
+    }
+    let bg_config = load_config();
+    let live = if bg_config.live_wallpaper { live::resolve(state) } else { None };
+    let state = live.as_ref().unwrap_or(state);
+    let portal =
+        if bg_config.use_document_portal { portal::resolve(conn, state).await } else { None };
+    let state = portal.as_ref().unwrap_or(state);
+    let mut other_accent = None;
+    for is_dark in [true, false] {
+        match apply_state(prev_state, state, is_dark, other_accent) {
+            Ok(result) => {
+                other_accent = Some(result.accent);
+                if let Err(err) = emit_theme_generated(conn, &result, is_dark).await {
+                    tracing::error!("Failed to emit the ThemeGenerated signal: {}", err);
+                }
+                if let Some(cmd) = &bg_config.post_apply_cmd {
+                    spawn_post_apply_cmd(cmd, &result, is_dark);
+                }
+            },
+            Err(err) => tracing::error!("Failed to apply the state: {}", err),
+        }
+    }
+}
+
+/// Writes the results last applied in each mode again, to get a theme on
+/// screen before the current wallpaper has been extracted.
+async fn apply_last_results(conn: &Connection) {
+    let bg_config = load_config();
+    for is_dark in [true, false] {
+        let generated = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)
+            .map_err(anyhow::Error::from)
+            .and_then(|c| Ok(c.get::<BgResult>(cache::last_result_key(is_dark))?))
+            .and_then(|result| theme_from_result(result, is_dark, &bg_config));
+        let generated = match generated {
+            Ok(generated) => generated,
+            Err(err) => {
+                tracing::debug!("No last result to apply: {}", err);
+                continue;
+            },
+        };
+        if let Err(err) = write_theme(generated.builder, is_dark, &bg_config) {
+            tracing::error!("Failed to apply the last result: {}", err);
+            continue;
+        }
+        tracing::info!(
+            "Applied the last {} result until the wallpaper is extracted",
+            if is_dark { "dark" } else { "light" }
+        );
+        if let Err(err) = emit_theme_generated(conn, &generated.result, is_dark).await {
+            tracing::error!("Failed to emit the ThemeGenerated signal: {}", err);
+        }
+    }
+}
+
+/// Runs `post_apply_cmd` in the background with the colors of the written theme
+/// in `$ACCENT`, `$BG`, `$NEUTRAL` and `$TEXT` as `#rrggbb`, and the mode in
+/// `$IS_DARK`. It is killed after [`POST_APPLY_TIMEOUT`].
+fn spawn_post_apply_cmd(cmd: &str, result: &BgResult, is_dark: bool) {
+    let mut command = tokio::process::Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .env("ACCENT", hex(result.accent))
+        .env("BG", hex(result.bg.color))
+        .env("NEUTRAL", hex(result.neutral))
+        .env("TEXT", result.text.map(hex).unwrap_or_default())
+        .env("IS_DARK", is_dark.to_string())
+        .kill_on_drop(true);
+    tokio::spawn(async move {
+        let status = match command.spawn() {
+            Ok(mut child) => tokio::time::timeout(POST_APPLY_TIMEOUT, child.wait()).await,
+            Err(err) => {
+                tracing::error!("Failed to run the post apply command: {}", err);
+                return;
+            },
+        };
+        match status {
+            Ok(Ok(status)) if status.success() => {},
+            Ok(Ok(status)) => tracing::error!("The post apply command failed with {}", status),
+            Ok(Err(err)) => tracing::error!("Failed to wait for the post apply command: {}", err),
+            Err(_) => tracing::error!("The post apply command timed out"),
+        }
+    });
+}
+
+/// Broadcasts the colors of a newly written theme, so that applets can show
+/// the current palette.
+async fn emit_theme_generated(
+    conn: &Connection,
+    result: &BgResult,
+    is_dark: bool,
+) -> anyhow::Result<()> {
+    let text = result.text.map(hex).unwrap_or_default();
+    conn.emit_signal(
+        None::<zbus::names::BusName>,
+        DBUS_PATH,
+        ID,
+        "ThemeGenerated",
+        &(is_dark, hex(result.accent), hex(result.bg.color), hex(result.neutral), text),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Formats a color as `#rrggbb`.
+fn hex(c: Srgb) -> String {
+    format!("#{:x}", c.into_format::<u8>())
+}
+
+/// Parses a color given as a `#rrggbb` hex string on the command line
+fn parse_hex(color: &str) -> anyhow::Result<Srgb> {
+    let color = Srgb::<u8>::from_str(color.trim())
+        .map_err(|err| anyhow::anyhow!("Invalid color {color}: {err}"))?;
+    Ok(color.into_format())
+}
+
+fn apply_state(
+    prev_state: Option<&State>,
+    state: &State,
+    is_dark: bool,
+    other_accent: Option<Srgb>,
+) -> anyhow::Result<BgResult> {
+    let bg_config = load_config();
+    let mut changed = changed_wallpapers(prev_state, state, bg_config.primary_output.as_deref());
+    if bg_config.multi_change == MultiChange::Primary {
+        let primary = bg_config
+            .primary_output
+            .as_ref()
+            .and_then(|primary| state.wallpapers.iter().find(|(output, _)| output == primary));
+        match primary {
+            Some(primary) => changed = vec![primary],
+            None => tracing::warn!("The primary output has no wallpaper, using the first"),
+        }
+    }
+    if changed.is_empty() {
         anyhow::bail!("No wallpapers found");
-    };
-    let cosmic_bg_config::Source::Path(ref path) = &w.1 else {
-        anyhow::bail!("No wallpaper path");
-    };
+    }
 
-    let bg_config = cosmic_config::Config::new(ID, MyConfig::VERSION)
-        .map(|c| match MyConfig::get_entry(&c) {
-            Ok(entry) => entry,
-            Err((errs, entry)) => {
-                for err in errs {
-                    tracing::error!("Failed to get the config: {}", err);
+    let mut themes = Vec::with_capacity(changed.len());
+    let mut last_err = None;
+    for (output, source) in changed.iter().copied() {
+        match apply_output(output, source, is_dark, other_accent, false) {
+            Ok(t) => {
+                themes.push((source, t));
+                if bg_config.multi_change != MultiChange::All {
+                    break;
                 }
-                entry
             },
+            Err(err) => {
+                tracing::error!("Failed to generate the theme for {}: {}", output, err);
+                last_err = Some(err);
+            },
+        }
+    }
+
+    // TODO write each output to its own config once cosmic-theme supports
+    // per-output themes. Until then the first generated theme is global.
+    let (source, generated) = match themes.into_iter().next() {
+        Some(theme) => theme,
+        None => {
+            let err = last_err.unwrap_or_else(|| anyhow::anyhow!("No theme generated"));
+            let source = &changed[0].1;
+            let Some(fallback) = fallback_theme(&load_config_for(source), is_dark) else {
+                return Err(err);
+            };
+            tracing::warn!("Using the fallback theme after failing to generate one: {}", err);
+            (source, fallback?)
+        },
+    };
+    // the profiles of the wallpaper also decide how the theme is written,
+    // saved and exported
+    let bg_config = load_config_for(source);
+    let theme = write_theme(generated.builder, is_dark, &bg_config)?;
+    if bg_config.rotate_accent_secs.is_some() {
+        rotate::save(is_dark, &generated.accent_candidates);
+    }
+    if bg_config.apply_last_on_startup {
+        save_last_result(&generated.result, is_dark);
+    }
+
+    let describe = |c: Srgb| {
+        if bg_config.name_colors_in_log {
+            format!("{} ({})", hex(c), names::nearest_name(c))
+        } else {
+            hex(c)
+        }
+    };
+    let result = &generated.result;
+    tracing::info!(
+        event = "theme-written",
+        is_dark,
+        accent = %hex(result.accent),
+        "Applied the {} theme with accent {}, neutral {} and text {}",
+        if is_dark { "dark" } else { "light" },
+        describe(result.accent),
+        describe(result.neutral),
+        result.text.map_or_else(|| "unchanged".to_string(), describe),
+    );
+
+    if let (Some(pywal_path), cosmic_bg_config::Source::Path(wallpaper)) =
+        (&bg_config.pywal_path, source)
+    {
+        // pywal has a single color scheme, so it follows the active mode
+        if is_active_mode(is_dark) {
+            if let Err(err) = export::pywal(&theme, wallpaper, pywal_path) {
+                tracing::error!("Failed to write the pywal colors: {}", err);
+            }
+        }
+    }
+
+    if let Some(gtk_css_path) = &bg_config.gtk_css_path {
+        // GTK 3 apps don't switch between dark and light colors either
+        if is_active_mode(is_dark) {
+            if let Err(err) = export::gtk_css(&theme, gtk_css_path) {
+                tracing::error!("Failed to write the GTK colors: {}", err);
+            }
+        }
+    }
+
+    Ok(generated.result)
+}
+
+/// Whether the desktop currently uses the dark or the light theme
+fn is_active_mode(is_dark: bool) -> bool {
+    let active_is_dark = ThemeMode::config()
+        .ok()
+        .and_then(|c| ThemeMode::get_entry(&c).ok())
+        .map_or(true, |mode| mode.is_dark);
+    active_is_dark == is_dark
+}
+
+/// The wallpapers that differ from the previous state, or the wallpaper of the
+/// primary output, or else the first wallpaper, if there is no previous state
+/// or nothing changed. The primary output comes first among several changed
+/// wallpapers.
+fn changed_wallpapers<'a>(
+    prev_state: Option<&State>,
+    state: &'a State,
+    primary_output: Option<&str>,
+) -> Vec<&'a (String, cosmic_bg_config::Source)> {
+    let is_primary = |(output, _): &&(String, cosmic_bg_config::Source)| {
+        primary_output.is_some_and(|primary| output == primary)
+    };
+    let mut changed: Vec<_> = prev_state
+        .map(|prev| {
+            state
+                .wallpapers
+                .iter()
+                .filter(|(k, v)| {
+                    prev.wallpapers.iter().find(|p| &p.0 == k).map_or(true, |prev_v| prev_v.1 != *v)
+                })
+                .collect()
         })
         .unwrap_or_default();
+    if changed.is_empty() {
+        let primary = state.wallpapers.iter().find(is_primary);
+        primary.or(state.wallpapers.first()).into_iter().collect()
+    } else {
+        // stable, so the other wallpapers keep their order
+        changed.sort_by_key(|w| !is_primary(w));
+        changed
+    }
+}
+
+/// Generates the theme for the wallpaper of a single output without writing
+/// it.
+///
+/// `other_accent` is the accent generated for the other mode. With
+/// `link_modes`, the accent takes its hue instead of being picked from the
+/// wallpaper, and with `prefer_consistent_accent_hue` the pick favors its hue.
+fn apply_output(
+    output: &str,
+    source: &cosmic_bg_config::Source,
+    is_dark: bool,
+    other_accent: Option<Srgb>,
+    dry_run: bool,
+) -> anyhow::Result<GeneratedTheme> {
+    let bg_config = load_config_for(source);
+    generate_theme(output, source, is_dark, other_accent, &bg_config, dry_run)
+}
 
-    let p = format!("{}_{}", path.to_string_lossy().replace("/", "_"), is_dark);
-    if use_saved_result(&p, is_dark).is_ok() {
-        return Ok(());
+/// Generates the theme like [`apply_output`], with the given config.
+///
+/// With `dry_run`, the caches are only read: the results, centroids, cluster
+/// counts and recent palettes are left as they are.
+fn generate_theme(
+    output: &str,
+    source: &cosmic_bg_config::Source,
+    is_dark: bool,
+    other_accent: Option<Srgb>,
+    bg_config: &MyConfig,
+    dry_run: bool,
+) -> anyhow::Result<GeneratedTheme> {
+    let cosmic_bg_config::Source::Path(ref path) = source else {
+        anyhow::bail!("No wallpaper path for {output}");
+    };
+
+    // video frames change over time, and a remote image can change behind the
+    // same URL, so they must not be served from the caches
+    let is_video = video::is_video(path);
+    let is_remote = remote::is_url(path);
+    let uncached = is_video || is_remote;
+
+    let p = cache::result_key(path, is_dark);
+    // results are only read back when they are being maintained, so that
+    // entries saved while the option was enabled can't go stale
+    // a rolling average changes with every wallpaper, so a saved result of the
+    // wallpaper alone doesn't apply
+    let rolling = bg_config.rolling_average_window > 1;
+    if bg_config.save_results && !uncached && !rolling {
+        if let Ok(generated) = use_saved_result(&p, is_dark, bg_config) {
+            return Ok(generated);
+        }
     }
 
-    let kmeans_p = format!("{}_kmeans", p);
+    // the clustering doesn't depend on the mode, so both modes share it
+    let kmeans_p = cache::kmeans_key(path);
 
-    let kmeans_config = cosmic_config::Config::new_state(ID, 1);
+    let kmeans_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION);
 
-    let mut res =
-        match kmeans_config.as_ref().ok().and_then(|c| c.get::<KmeanState>(&kmeans_p).ok()) {
-            Some(res) if !res.0.is_empty() => res.0,
-            _ => {
-                let img = image::io::Reader::open(path)?.with_guessed_format()?.decode()?;
-
-                // resize to width == 256
-                let dst_width = 256;
-                let dst_height =
-                    (dst_width as f32 / img.width() as f32 * img.height() as f32) as u32;
-                let mut dst_image = Image::new(dst_width, dst_height, img.pixel_type().unwrap());
-                let mut resizer = Resizer::new();
-                resizer.resize(&img, &mut dst_image, None)?;
-
-                let img: Vec<Lab> = dst_image
-                    .into_vec()
-                    .chunks(3)
-                    .map(|p| {
-                        let rgb = Srgb::<u8>::new(p[0], p[1], p[2]);
-                        rgb.into_format().into_color()
-                    })
-                    .collect();
-
-                let mut results = Vec::new();
-                let seed = 42;
-                // TODO elbow method
-                let mut best_result = Kmeans::new();
-                for i in 0..2 {
-                    let run_result = get_kmeans(8, 40, 10., false, &img, seed + i as u64);
-                    if run_result.score < best_result.score {
-                        best_result = run_result;
-                    }
+    let cached = if uncached {
+        None
+    } else {
+        kmeans_config.as_ref().ok().and_then(|c| c.get::<KmeanState>(&kmeans_p).ok())
+    };
+    let (res, shares) = match cached {
+        Some(res) if res.is_valid() => (res.0, res.1),
+        _ => {
+            tracing::info!(
+                event = "extraction-started",
+                wallpaper = %path.display(),
+                is_dark,
+                "Extracting the colors of {}",
+                path.display()
+            );
+            // a remote wallpaper is decoded from its downloaded copy
+            let fetched =
+                if is_remote { Some(remote::fetch(path, bg_config.allow_remote)?) } else { None };
+            let local = fetched.as_deref().unwrap_or(path);
+            let animated =
+                if is_video { None } else { animation::frames(local, bg_config.animated_frames)? };
+            let frames = if let Some(frames) = animated {
+                frames
+            } else if is_video {
+                vec![video::grab_frame(local, bg_config.video_frame_cmd.as_deref())?]
+            } else if svg::is_svg(local) {
+                vec![svg::rasterize(local, bg_config.sample_width)?]
+            } else if let Some(thumb) = bg_config
+                .use_desktop_thumbnails
+                .then(|| thumbnail::find(local, bg_config.sample_width))
+                .flatten()
+            {
+                // already downscaled, and much cheaper to decode
+                tracing::debug!("Using the thumbnail {}", thumb.display());
+                vec![icc::decode(&thumb, bg_config.use_icc_profile)?]
+            } else {
+                vec![icc::decode(local, bg_config.use_icc_profile)?]
+            };
+
+            // the frames of an animation are pooled as one tall image, since
+            // they all have the same size
+            let mut img = Vec::new();
+            let mut width = 0;
+            for mut frame in frames {
+                if bg_config.pre_blur_sigma > 0. {
+                    // smooth out fine detail that would otherwise produce
+                    // spurious centroids
+                    frame = frame.blur(bg_config.pre_blur_sigma);
                 }
-                results.push(best_result);
-
-                let Some(kmeans) = results.into_iter().last() else {
-                    anyhow::bail!("No kmeans result");
-                };
-                let centroids = kmeans.centroids.clone();
-                let mut res = Lab::sort_indexed_colors(&centroids, &kmeans.indices);
-                res.sort_unstable_by(|a, b| (b.percentage).total_cmp(&a.percentage));
-
-                let mut res = res.into_iter().map(|c| c.centroid).collect::<Vec<Lab>>();
-                // move avoid colors to the end
-                let avoid = if is_dark { &bg_config.avoid_dark } else { &bg_config.avoid_light };
-                let mut avoid_colors = Vec::new();
-                res.retain(|c| {
-                    if avoid.iter().any(|a| *a == (*c).into_color()) {
-                        avoid_colors.push(*c);
-                        false
-                    } else {
-                        true
-                    }
-                });
-                res.extend(avoid_colors);
-
-                // move low chroma colors to the end
-                let mut low_chroma = Vec::new();
-                res.retain(|c| {
-                    let lch = Lch::from_color(*c);
-                    if lch.chroma < 10. {
-                        low_chroma.push(*c);
-                        false
-                    } else {
-                        true
-                    }
-                });
-                res.extend(low_chroma);
-
-                if bg_config.save_kmeans {
-                    if let Ok(kmeans_config) = kmeans_config {
-                        if let Err(err) = kmeans_config.set(&kmeans_p, KmeanState(res.clone())) {
-                            tracing::error!("Failed to save the kmeans result: {}", err);
-                        }
+                let (pixels, frame_width) =
+                    sample_pixels(frame, bg_config.sample_width, bg_config.square_sample)?;
+                img.extend(pixels);
+                width = frame_width;
+            }
+            let img = if bg_config.entropy_crop {
+                let (crop, crop_width) = saliency::entropy_crop(&img, width);
+                width = crop_width;
+                crop
+            } else {
+                img
+            };
+            // the edge pixels are no longer a grid of `width` columns
+            let img = if bg_config.edge_sampling { edges::edge_pixels(&img, width) } else { img };
+            let region_weighting = bg_config.region_weighting && !bg_config.edge_sampling;
+
+            let kmeans = if bg_config.auto_clusters {
+                let clusters_p = cache::clusters_key(path);
+                let state_config = kmeans_config.as_ref().ok();
+                let (k, kmeans) =
+                    auto_cluster_count(&img, state_config, &clusters_p, bg_config, !dry_run);
+                tracing::info!("Using {} clusters for {}", k, path.display());
+                kmeans
+            } else {
+                run_kmeans(DEFAULT_CLUSTERS, &img, bg_config)
+            };
+            let mut res = Lab::sort_indexed_colors(&kmeans.centroids, &kmeans.indices);
+            let (mut indices, mut clusters) = (kmeans.indices, kmeans.centroids.len());
+            if bg_config.merge_similar_centroids {
+                (res, indices) = merge_similar_centroids(res, &indices, bg_config.merge_distance);
+                clusters = res.len();
+            }
+            res.sort_unstable_by(|a, b| (b.percentage).total_cmp(&a.percentage));
+            if region_weighting {
+                // a color in one large region themes better than the same area
+                // scattered as noise
+                let largest = region::largest_regions(&indices, width, clusters);
+                res.sort_by_key(|c| std::cmp::Reverse(largest[c.index as usize]));
+            }
+
+            let (res, shares): (Vec<Lab>, Vec<f32>) =
+                res.into_iter().map(|c| (c.centroid, c.percentage)).unzip();
+            tracing::info!(
+                event = "extraction-finished",
+                wallpaper = %path.display(),
+                is_dark,
+                clusters = res.len(),
+                "Extracted {} colors from {}",
+                res.len(),
+                path.display()
+            );
+
+            if bg_config.save_kmeans && !uncached && !dry_run {
+                if let Ok(kmeans_config) = &kmeans_config {
+                    if let Err(err) =
+                        kmeans_config.set(&kmeans_p, KmeanState(res.clone(), shares.clone()))
+                    {
+                        tracing::error!("Failed to save the kmeans result: {}", err);
                     }
                 }
+            }
 
-                res
-            },
-        };
+            (res, shares)
+        },
+    };
+    // the share of the image of each centroid, looked up by the centroid as
+    // the candidates get reordered and filtered
+    let shares: Vec<(Lab, f32)> = res.iter().copied().zip(shares).collect();
+    let avoid = avoid::colors(if is_dark { &bg_config.avoid_dark } else { &bg_config.avoid_light });
+    let mut res = reorder_candidates(res, &avoid);
+    if rolling {
+        let key = cache::recent_key(is_dark);
+        let window = bg_config.rolling_average_window;
+        res = rolling_average(kmeans_config.as_ref().ok(), key, path, res, window, !dry_run);
+    }
+    // the tail is the least common and the avoided colors, which only add
+    // noise to the selection
+    res.truncate(bg_config.max_candidates());
+    if bg_config.monochrome {
+        // keep only the tonality of the wallpaper
+        res = res.into_iter().map(|c| Lab::new(c.l, 0., 0.)).collect();
+    }
 
     let (builder_config, default) = if is_dark {
         (ThemeBuilder::dark_config()?, Theme::dark_default())
@@ -319,79 +1016,119 @@ fn apply_state(prev_state: Option<&State>, state: &State, is_dark: bool) -> anyh
             entry
         },
     };
+    let current = t.clone();
 
     // BG
     let default_window_bg = Lch::from_color(default.background.base);
+    let avoid_bg =
+        avoid::colors(if is_dark { &bg_config.avoid_dark } else { &bg_config.avoid_light });
 
-    for c in &res {
-        // make sure not in avoid after adjusting
-        let mut new_window_bg: Lch = (*c).into_color();
-        if (new_window_bg.chroma - default_window_bg.chroma).abs() > 15. {
-            new_window_bg.chroma = default_window_bg.chroma + 15.;
-            new_window_bg = new_window_bg.clamp();
-        }
-        if is_dark
-            && bg_config.avoid_dark.iter().any(|a| {
-                let a = Lch::from_color(*a);
-                let hue_diff = (new_window_bg.hue.into_inner() - a.hue.into_inner()).abs() % 180.;
-                (new_window_bg.chroma - a.chroma).powf(2.) + (hue_diff).powf(2.) < 666.
-                    && hue_diff < 20.
-            })
-            || !is_dark
-                && bg_config.avoid_light.iter().any(|a| {
-                    let a = Lch::from_color(*a);
-                    let hue_diff =
-                        (new_window_bg.hue.into_inner() - a.hue.into_inner()).abs() % 180.;
-                    (new_window_bg.chroma - a.chroma).powf(2.) + (hue_diff).powf(2.) < 666.
-                        && hue_diff < 20.
-                })
-        {
-            continue;
-        }
-
-        new_window_bg.l = default_window_bg.l;
-
-        t = t.bg_color(new_window_bg.into_color());
+    // the most common color is usually the sky or background of the
+    // wallpaper, found before the background takes it from the candidates
+    let dominant = dominant_color(&shares, bg_config);
 
-        res.retain(|c| {
-            let c = Lch::from_color(*c);
-            (c.hue - new_window_bg.hue).into_inner().abs() > 10.
-        });
-        break;
+    let mut bg: Option<Srgba> = None;
+    if !bg_config.preserve_bg {
+        bg = pick_bg(&mut res, default_window_bg, &avoid_bg, bg_config).map(IntoColor::into_color);
+    }
+    let bg = match bg {
+        Some(bg) => bg,
+        None if bg_config.preserve_bg => t.bg_color.unwrap_or(default.background.base),
+        None => {
+            tracing::warn!("Every background candidate is avoided, using the default background");
+            default.background.base
+        },
+    };
+    t = t.bg_color(bg);
+
+    // a similar wallpaper doesn't need a new theme as long as the current one
+    // stays readable on it
+    if bg_config.lazy_update
+        && still_readable(
+            current.accent,
+            current.text_tint,
+            bg.into_color(),
+            bg_config.contrast_model,
+            bg_config.min_contrast(),
+        )
+    {
+        tracing::info!("The current theme is still readable on {}, keeping it", path.display());
+        let result = BgResult {
+            accent: current.accent.unwrap_or(default.accent.base.into_color()),
+            bg: current.bg_color.unwrap_or(default.background.base),
+            neutral: current.neutral_tint.unwrap_or(default.palette.neutral_5.into_color()),
+            text: current.text_tint,
+        };
+        return Ok(GeneratedTheme { builder: current, result, accent_candidates: Vec::new() });
     }
 
     // ACCENT
-    let avoid =
-        if is_dark { &bg_config.avoid_accents_dark } else { &bg_config.avoid_accents_light };
+    let avoid = avoid::colors(if is_dark {
+        &bg_config.avoid_accents_dark
+    } else {
+        &bg_config.avoid_accents_light
+    });
 
-    let accent_res =
-        if bg_config.randomize { left_skewed_shuffle(res.clone(), Some(3)) } else { res.clone() };
+    let accent_pool = accent_pool(&res, dominant, bg_config);
+    let accent_res = if bg_config.randomize {
+        skewed_shuffle(accent_pool, Some(3), bg_config.shuffle_skew)
+    } else {
+        accent_pool
+    };
+
+    // a preserved background is known, so the accent can target it exactly
+    let accent_bg: Lch =
+        if bg_config.preserve_bg { bg.into_color() } else { default.background.base.into_color() };
+    let fallback_accent: Lch =
+        bg_config.fallback_accent.map_or(default.accent.base.into_color(), |c| c.into_color());
+    let linked_accent = other_accent.filter(|_| bg_config.link_modes);
+    let consistent_hue = other_accent
+        .filter(|_| bg_config.prefer_consistent_accent_hue)
+        .map(|c| Lch::from_color(c).hue);
+    let mut accent = match linked_accent {
+        // keep the hue of the other mode, only adjusting the lightness
+        Some(linked) => {
+            let linked: Lch = linked.into_color();
+            let adjusted = adjust_lightness_for_contrast(
+                linked,
+                accent_bg,
+                bg_config.contrast_model,
+                bg_config.min_contrast(),
+                bg_config.contrast_search_steps,
+            );
+            (Lab::from_color(linked), adjusted)
+        },
+        None => {
+            let early_accept_len =
+                early_accept_len(res.len(), bg_config.accent_early_accept_fraction);
+            let picked = pick_accent(
+                &accent_res,
+                &shares,
+                early_accept_len,
+                accent_bg,
+                &avoid,
+                consistent_hue,
+                bg_config,
+            );
+            match (picked, bg_config.fallback_accent) {
+                (Some(picked), Some(_))
+                    if is_near_avoid(picked.1, &avoid, &bg_config.avoid_tolerance) =>
+                {
+                    tracing::warn!("Every accent candidate is avoided, using the fallback accent");
+                    (Lab::from_color(fallback_accent), fallback_accent)
+                },
+                (Some(picked), _) => picked,
+                (None, _) => {
+                    tracing::warn!("No accent candidates are left, using the fallback accent");
+                    (Lab::from_color(fallback_accent), fallback_accent)
+                },
+            }
+        },
+    };
+    accent.1 = finish_accent(accent.1, accent_bg, fallback_accent, is_dark, bg_config);
+    let score = themeability(&res, accent.1, accent_bg, bg_config);
+    tracing::info!("Themeability of {}: {:.2}", path.display(), score);
 
-    let mut accent: (Lab, Lch) = (accent_res[0], accent_res[0].into_color());
-    let mut best = f32::MIN;
-    for (i, color) in accent_res.iter().enumerate() {
-        let lch_orig = Lch::from_color(*color);
-        let adjusted = adjust_lightness_for_contrast(
-            (*color).into_color(),
-            default.background.base.into_color(),
-            4.5,
-        );
-        let mut score = adjusted.chroma;
-        if avoid.iter().any(|c| {
-            let c = Lch::from_color(*c);
-            let hue_diff = (adjusted.hue.into_inner() - c.hue.into_inner()).abs() % 180.;
-            (adjusted.chroma - c.chroma).powf(2.) + (hue_diff).powf(2.) < 666. && hue_diff < 20.
-        }) {
-            score /= 10.;
-        } else if lch_orig.chroma > 60. && i <= res.len() / 3 {
-            accent = (*color, adjusted);
-            break;
-        }
-        if score > best {
-            best = score;
-            accent = (*color, adjusted);
-        }
-    }
     let max_hue_diff = res
         .iter()
         .map(|c| {
@@ -399,152 +1136,1008 @@ fn apply_state(prev_state: Option<&State>, state: &State, is_dark: bool) -> anyh
             (c.hue - accent.1.hue).into_inner().abs()
         })
         .max_by(|a, b| a.total_cmp(b))
-        .unwrap();
+        .unwrap_or_default();
 
-    res.retain(|c| {
-        let c = Lch::from_color(*c);
-        (c.hue - accent.1.hue).into_inner().abs() > max_hue_diff / 6.
-    });
+    let unfiltered = bg_config.neutral_text_before_hue_spread.then(|| res.clone());
+    if !bg_config.monochrome {
+        res.retain(|c| {
+            let c = Lch::from_color(*c);
+            (c.hue - accent.1.hue).into_inner().abs() > max_hue_diff / 6.
+        });
+    }
+    // the spread filter can leave too few centroids for the neutral and text
+    let res = unfiltered.unwrap_or(res);
 
-    let accent = Srgb::from_color(accent.1);
+    let mut accent = Srgb::from_color(fit_gamut(accent.1, bg_config.gamut_map));
     t = t.accent(accent);
 
-    let mut res = if bg_config.randomize { left_skewed_shuffle(res, None) } else { res };
+    let mut res =
+        if bg_config.randomize { skewed_shuffle(res, None, bg_config.shuffle_skew) } else { res };
 
     // NEUTRAL
-    let mut neutral = default.palette.neutral_5;
-
-    for c in &res {
-        let c_lch = Lch::from_color(*c);
-        if c_lch.chroma > 10. {
+    let mut neutral =
+        bg_config.fallback_neutral.map_or(default.palette.neutral_5, |c| c.into_color());
+
+    if bg_config.derive_neutral {
+        for c in &res {
+            let mut c_lch = Lch::from_color(*c);
+            if c_lch.chroma > 10. {
+                // keep the window chrome subtle even on saturated wallpapers
+                c_lch.chroma = c_lch.chroma.min(bg_config.neutral_max_chroma);
+                neutral = c_lch.clamp().into_color();
+                break;
+            }
+        }
+        if bg_config.monochrome {
+            let mut c_lch = Lch::from_color(neutral);
+            c_lch.chroma = 0.;
             neutral = c_lch.into_color();
-            break;
         }
+        neutral = cap_neutral_chroma(neutral, is_dark, bg_config).into_color();
+
+        t = t.neutral_tint(neutral.into_color());
+    } else {
+        // leave the default neutral grey untouched
+        t.neutral_tint = None;
     }
 
-    t = t.neutral_tint(neutral.into_color());
+    // the accent must stand out from the window chrome it was tinted alike
+    let min_distance = bg_config.min_accent_neutral_distance;
+    let neutral_lab: Lab = neutral.into_color();
+    if bg_config.derive_neutral && lab_distance(accent.into_color(), neutral_lab) < min_distance {
+        let distinct: Vec<Lab> = accent_res
+            .iter()
+            .copied()
+            .filter(|c| lab_distance(*c, neutral_lab) >= min_distance)
+            .collect();
+        let repicked = match pick_accent(
+            &distinct,
+            &shares,
+            0,
+            accent_bg,
+            &avoid,
+            consistent_hue,
+            bg_config,
+        ) {
+            Some((_, c)) => {
+                tracing::info!("The accent is too close to the neutral, picking another");
+                c
+            },
+            None => {
+                tracing::info!("The accent is too close to the neutral, shifting its hue");
+                let mut c = Lch::from_color(accent);
+                c.hue += 30.;
+                c
+            },
+        };
+        let repicked = finish_accent(repicked, accent_bg, fallback_accent, is_dark, bg_config);
+        accent = Srgb::from_color(fit_gamut(repicked, bg_config.gamut_map));
+        t = t.accent(accent);
+    }
 
-    // TEXT
-    let text = res.remove(0);
-    t = t.text_tint(text.into_color());
+    let accent_candidates = if bg_config.rotate_accent_secs.is_some() {
+        rotate::candidates(
+            accent,
+            &accent_res,
+            accent_bg,
+            fallback_accent,
+            is_dark,
+            &avoid,
+            bg_config,
+        )
+    } else {
+        Vec::new()
+    };
 
-    let result = BgResult {
-        accent,
-        bg: t.bg_color.unwrap(),
-        neutral: t.neutral_tint.unwrap(),
-        text: Some(text.into_color()),
+    // TEXT
+    let text: Option<Srgb> = match bg_config.text_tint_strategy {
+        // the filters above can remove every centroid, which leaves the text
+        // tint as it is
+        TextTintStrategy::FirstCandidate => (!res.is_empty()).then(|| res.remove(0).into_color()),
+        TextTintStrategy::NeutralDerived => {
+            Some(derived_text_tint(neutral.into_color(), bg.into_color(), bg_config))
+        },
+        TextTintStrategy::AccentDerived => {
+            Some(derived_text_tint(accent.into_color(), bg.into_color(), bg_config))
+        },
     };
-    if bg_config.save_results {
-        let my_config = cosmic_config::Config::new_state(ID, 1)?;
-        if let Err(err) = my_config.set(&p, result) {
+    if let Some(text) = text {
+        t = t.text_tint(text);
+    }
+
+    let result = BgResult { accent, bg, neutral: neutral.into_color(), text };
+    if bg_config.save_results && !uncached && !dry_run {
+        let my_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)?;
+        let retries = bg_config.config_write_retries;
+        if let Err(err) = retry_write(retries, || my_config.set(&p, result)) {
             tracing::error!("Failed to save the result: {}", err);
         }
+        if bg_config.save_thumbnails {
+            if let Err(err) = cache::save_thumbnail(&p, &result) {
+                tracing::error!("Failed to save the thumbnail: {}", err);
+            }
+        }
     }
 
     // PALETTE
-    // match chroma and lightness to accent for all palette colors
+    if bg_config.harmonize_palette {
+        sync_palette(&mut t, accent, bg_config);
+    }
+
+    bg_config.tint_scope.apply(&mut t, &default);
+    bg_config.write_fields.keep_disabled(&mut t, &current);
+    Ok(GeneratedTheme { builder: t, result, accent_candidates })
+}
+
+/// Matches the chroma and lightness of all palette colors to the accent,
+/// placing the hues according to `palette_harmony`. With
+/// `preserve_semantic_red`, the reds only take the lightness of the accent, so
+/// that destructive actions stay red. With `equalize_palette_chroma`, the
+/// chroma is lowered to what every color can reach in the sRGB gamut.
+fn sync_palette(t: &mut ThemeBuilder, accent: Srgb, bg_config: &MyConfig) {
+    let harmony = bg_config.palette_harmony;
+    let preserve_red = bg_config.preserve_semantic_red;
+    let fit = bg_config.gamut_map;
+    let mut accent = Lch::from_color(accent);
+    let reachable = reachable_palette_chroma(t, accent, harmony, preserve_red);
+    if bg_config.equalize_palette_chroma && reachable < accent.chroma {
+        tracing::debug!(
+            "Lowering the palette chroma from {:.1} to {:.1}",
+            accent.chroma,
+            reachable
+        );
+        accent.chroma = reachable;
+    }
+
     let blue = t.palette.as_mut().accent_blue;
-    t.palette.as_mut().accent_blue = sync_chroma_lightness(accent, blue);
+    t.palette.as_mut().accent_blue = sync_palette_color(accent, blue, harmony, fit);
 
     let green = t.palette.as_mut().accent_green;
-    t.palette.as_mut().accent_green = sync_chroma_lightness(accent, green);
+    t.palette.as_mut().accent_green = sync_palette_color(accent, green, harmony, fit);
 
     let orange = t.palette.as_mut().accent_orange;
-    t.palette.as_mut().accent_orange = sync_chroma_lightness(accent, orange);
+    t.palette.as_mut().accent_orange = sync_palette_color(accent, orange, harmony, fit);
 
     let purple = t.palette.as_mut().accent_purple;
-    t.palette.as_mut().accent_purple = sync_chroma_lightness(accent, purple);
+    t.palette.as_mut().accent_purple = sync_palette_color(accent, purple, harmony, fit);
 
     let red = t.palette.as_mut().accent_red;
-    t.palette.as_mut().accent_red = sync_chroma_lightness(accent, red);
+    t.palette.as_mut().accent_red = if preserve_red {
+        sync_lightness(accent, red, fit)
+    } else {
+        sync_palette_color(accent, red, harmony, fit)
+    };
 
     let yellow = t.palette.as_mut().accent_yellow;
-    t.palette.as_mut().accent_yellow = sync_chroma_lightness(accent, yellow);
+    t.palette.as_mut().accent_yellow = sync_palette_color(accent, yellow, harmony, fit);
 
     let ext_blue = t.palette.as_mut().ext_blue;
-    t.palette.as_mut().ext_blue = sync_chroma_lightness(accent, ext_blue);
+    t.palette.as_mut().ext_blue = sync_palette_color(accent, ext_blue, harmony, fit);
 
     let ext_indigo = t.palette.as_mut().ext_indigo;
-    t.palette.as_mut().ext_indigo = sync_chroma_lightness(accent, ext_indigo);
+    t.palette.as_mut().ext_indigo = sync_palette_color(accent, ext_indigo, harmony, fit);
 
     let ext_orange = t.palette.as_mut().ext_orange;
-    t.palette.as_mut().ext_orange = sync_chroma_lightness(accent, ext_orange);
+    t.palette.as_mut().ext_orange = sync_palette_color(accent, ext_orange, harmony, fit);
 
     let ext_pink = t.palette.as_mut().ext_pink;
-    t.palette.as_mut().ext_pink = sync_chroma_lightness(accent, ext_pink);
+    t.palette.as_mut().ext_pink = sync_palette_color(accent, ext_pink, harmony, fit);
 
     let ext_purple = t.palette.as_mut().ext_purple;
-    t.palette.as_mut().ext_purple = sync_chroma_lightness(accent, ext_purple);
+    t.palette.as_mut().ext_purple = sync_palette_color(accent, ext_purple, harmony, fit);
 
     let ext_warm_grey = t.palette.as_mut().ext_warm_grey;
-    t.palette.as_mut().ext_warm_grey = sync_chroma_lightness(accent, ext_warm_grey);
+    t.palette.as_mut().ext_warm_grey = sync_palette_color(accent, ext_warm_grey, harmony, fit);
 
     let ext_yellow = t.palette.as_mut().ext_yellow;
-    t.palette.as_mut().ext_yellow = sync_chroma_lightness(accent, ext_yellow);
+    t.palette.as_mut().ext_yellow = sync_palette_color(accent, ext_yellow, harmony, fit);
 
     let bright_green = t.palette.as_mut().bright_green;
-    t.palette.as_mut().bright_green =
-        Lch::from_color(sync_chroma_lightness(accent, bright_green)).saturate(0.5).into_color();
+    t.palette.as_mut().bright_green = fit_gamut(
+        Lch::from_color(sync_palette_color(accent, bright_green, harmony, fit)).saturate(0.5),
+        fit,
+    )
+    .into_color();
 
     let bright_orange = t.palette.as_mut().bright_orange;
-    t.palette.as_mut().bright_orange =
-        Lch::from_color(sync_chroma_lightness(accent, bright_orange)).saturate(0.5).into_color();
+    t.palette.as_mut().bright_orange = fit_gamut(
+        Lch::from_color(sync_palette_color(accent, bright_orange, harmony, fit)).saturate(0.5),
+        fit,
+    )
+    .into_color();
 
     let bright_red = t.palette.as_mut().bright_red;
-    t.palette.as_mut().bright_red =
-        Lch::from_color(sync_chroma_lightness(accent, bright_red)).saturate(0.5).into_color();
+    t.palette.as_mut().bright_red = if preserve_red {
+        sync_lightness(accent, bright_red, fit)
+    } else {
+        fit_gamut(
+            Lch::from_color(sync_palette_color(accent, bright_red, harmony, fit)).saturate(0.5),
+            fit,
+        )
+        .into_color()
+    };
 
     let accent_indigo = t.palette.as_mut().accent_indigo;
-    t.palette.as_mut().accent_indigo = sync_chroma_lightness(accent, accent_indigo);
+    t.palette.as_mut().accent_indigo = sync_palette_color(accent, accent_indigo, harmony, fit);
 
     let accent_pink = t.palette.as_mut().accent_pink;
-    t.palette.as_mut().accent_pink = sync_chroma_lightness(accent, accent_pink);
+    t.palette.as_mut().accent_pink = sync_palette_color(accent, accent_pink, harmony, fit);
 
     let accent_warm_grey = t.palette.as_mut().accent_warm_grey;
-    t.palette.as_mut().accent_warm_grey = sync_chroma_lightness(accent, accent_warm_grey);
+    t.palette.as_mut().accent_warm_grey =
+        sync_palette_color(accent, accent_warm_grey, harmony, fit);
 
     let accent_yellow = t.palette.as_mut().accent_yellow;
-    t.palette.as_mut().accent_yellow = sync_chroma_lightness(accent, accent_yellow);
+    t.palette.as_mut().accent_yellow = sync_palette_color(accent, accent_yellow, harmony, fit);
+}
+
+/// The chroma all the harmonized palette colors can reach at the lightness of
+/// `accent`, at most the chroma of the accent. The colors the sRGB gamut holds
+/// below the chroma of the accent are logged, since they will look washed out
+/// next to the others.
+fn reachable_palette_chroma(
+    t: &ThemeBuilder,
+    accent: Lch,
+    harmony: PaletteHarmony,
+    preserve_red: bool,
+) -> f32 {
+    let p = t.palette.as_ref();
+    let colors = [
+        ("accent_blue", p.accent_blue),
+        ("accent_green", p.accent_green),
+        ("accent_orange", p.accent_orange),
+        ("accent_purple", p.accent_purple),
+        ("accent_red", p.accent_red),
+        ("accent_yellow", p.accent_yellow),
+        ("accent_indigo", p.accent_indigo),
+        ("accent_pink", p.accent_pink),
+        ("accent_warm_grey", p.accent_warm_grey),
+        ("ext_blue", p.ext_blue),
+        ("ext_indigo", p.ext_indigo),
+        ("ext_orange", p.ext_orange),
+        ("ext_pink", p.ext_pink),
+        ("ext_purple", p.ext_purple),
+        ("ext_warm_grey", p.ext_warm_grey),
+        ("ext_yellow", p.ext_yellow),
+    ];
+    colors
+        .iter()
+        // a preserved red keeps its own chroma
+        .filter(|(name, _)| !(preserve_red && *name == "accent_red"))
+        .map(|(name, c)| {
+            let hue = harmony.place_hue(accent.hue, Lch::from_color(*c).hue);
+            let reachable = max_chroma_in_gamut(accent.l, hue, accent.chroma);
+            if reachable < accent.chroma - 1. {
+                tracing::debug!(
+                    "The palette color {} can only reach chroma {:.1} of {:.1}",
+                    name,
+                    reachable,
+                    accent.chroma
+                );
+            }
+            reachable
+        })
+        .fold(accent.chroma, f32::min)
+}
+
+/// The highest chroma up to `max` of the hue at lightness `l` that is in the
+/// sRGB gamut
+fn max_chroma_in_gamut(l: f32, hue: LabHue, max: f32) -> f32 {
+    let in_gamut = |chroma| Srgb::from_color_unclamped(Lch::new(l, chroma, hue)).is_within_bounds();
+    if in_gamut(max) {
+        return max;
+    }
+    let (mut lo, mut hi) = (0., max);
+    for _ in 0..GAMUT_SEARCH_STEPS {
+        let mid = (lo + hi) / 2.;
+        if in_gamut(mid) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
 
-    t.write_entry(&builder_config)?;
+/// Moves the avoided and the low chroma centroids to the end, keeping the order
+/// of the others.
+fn reorder_candidates(mut res: Vec<Lab>, avoid: &[Srgb]) -> Vec<Lab> {
+    let mut avoid_colors = Vec::new();
+    res.retain(|c| {
+        if avoid.iter().any(|a| *a == (*c).into_color()) {
+            avoid_colors.push(*c);
+            false
+        } else {
+            true
+        }
+    });
+    res.extend(avoid_colors);
 
-    let theme = t.build();
+    let mut low_chroma = Vec::new();
+    res.retain(|c| {
+        let lch = Lch::from_color(*c);
+        if lch.chroma < 10. {
+            low_chroma.push(*c);
+            false
+        } else {
+            true
+        }
+    });
+    res.extend(low_chroma);
+    res
+}
 
-    let theme_config = if theme.is_dark { Theme::dark_config() } else { Theme::light_config() }?;
+/// Runs `write`, retrying up to `retries` times with a short backoff, since
+/// writes can fail while another process writes the same config.
+fn retry_write<T, E>(retries: u32, mut write: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match write() {
+            Err(_) if attempt < retries => {
+                attempt += 1;
+                std::thread::sleep(WRITE_RETRY_BACKOFF * attempt);
+            },
+            res => return res,
+        }
+    }
+}
 
-    theme.write_entry(&theme_config)?;
+fn write_theme(t: ThemeBuilder, is_dark: bool, bg_config: &MyConfig) -> anyhow::Result<Theme> {
+    let retries = bg_config.config_write_retries;
+    let builder_config =
+        if is_dark { ThemeBuilder::dark_config()? } else { ThemeBuilder::light_config()? };
+    // built before anything is written, so that the theme apps render follows
+    // the builder as closely as possible
+    let mut theme = t.clone().build();
+    if theme.is_dark != is_dark {
+        // the palette of an edited builder can belong to the other mode, which
+        // must not make the theme overwrite the config of that mode
+        tracing::warn!(
+            "The {} builder builds a {} theme, writing it as {} anyway",
+            if is_dark { "dark" } else { "light" },
+            if theme.is_dark { "dark" } else { "light" },
+            if is_dark { "dark" } else { "light" },
+        );
+        theme.is_dark = is_dark;
+    }
+    let theme_config = if is_dark { Theme::dark_config() } else { Theme::light_config() }?;
 
-    Ok(())
+    // only entries that could be read completely are worth restoring
+    let prior = bg_config.rollback_on_failure.then(|| {
+        (ThemeBuilder::get_entry(&builder_config).ok(), Theme::get_entry(&theme_config).ok())
+    });
+    let written = if bg_config.atomic_writes {
+        retry_write(retries, || write_derived_keys(&t, &builder_config))
+    } else {
+        retry_write(retries, || t.write_entry(&builder_config))
+    }
+    .and_then(|()| retry_write(retries, || theme.write_entry(&theme_config)));
+
+    if let Err(err) = written {
+        if let Some((builder, prior_theme)) = prior {
+            roll_back(builder, prior_theme, &builder_config, &theme_config);
+        }
+        return Err(err.into());
+    }
+    Ok(theme)
+}
+
+/// Writes back the entries read before a failed write, so that the theme
+/// isn't left half-updated.
+fn roll_back(
+    builder: Option<ThemeBuilder>,
+    theme: Option<Theme>,
+    builder_config: &Config,
+    theme_config: &Config,
+) {
+    tracing::warn!("Writing the theme failed, restoring the previous theme");
+    if let Some(builder) = builder {
+        if let Err(err) = builder.write_entry(builder_config) {
+            tracing::error!("Failed to restore the theme builder: {}", err);
+        }
+    }
+    if let Some(theme) = theme {
+        if let Err(err) = theme.write_entry(theme_config) {
+            tracing::error!("Failed to restore the theme: {}", err);
+        }
+    }
+}
+
+/// Writes the keys of the builder the daemon derives from the wallpaper in one
+/// transaction, skipping those that already hold the value.
+///
+/// Every key is written atomically by the config layer, so only the changed
+/// keys being committed together are left to keep the entry consistent.
+fn write_derived_keys(t: &ThemeBuilder, config: &Config) -> Result<(), cosmic_config::Error> {
+    let current = ThemeBuilder::get_entry(config).unwrap_or_else(|(_, entry)| entry);
+    let tx = config.transaction();
+    if current.accent != t.accent {
+        tx.set("accent", t.accent)?;
+    }
+    if current.bg_color != t.bg_color {
+        tx.set("bg_color", t.bg_color)?;
+    }
+    if current.neutral_tint != t.neutral_tint {
+        tx.set("neutral_tint", t.neutral_tint)?;
+    }
+    if current.text_tint != t.text_tint {
+        tx.set("text_tint", t.text_tint)?;
+    }
+    if current.palette != t.palette {
+        tx.set("palette", t.palette.clone())?;
+    }
+    if current.primary_container_bg != t.primary_container_bg {
+        tx.set("primary_container_bg", t.primary_container_bg)?;
+    }
+    if current.secondary_container_bg != t.secondary_container_bg {
+        tx.set("secondary_container_bg", t.secondary_container_bg)?;
+    }
+    tx.commit()
+}
+
+/// Downscales the image to `width` and converts its pixels to Lab, returning
+/// them with the width of each row.
+///
+/// The image is converted to RGB8 first, so that any pixel type the decoder
+/// can produce (16-bit, grey, palette, alpha...) is handled by the resizer.
+/// With `square`, the image is center-cropped to a square first, so that very
+/// wide or tall images are sampled evenly in both directions. Images no wider
+/// than `width` are sampled at full resolution, since upscaling would only add
+/// interpolated colors.
+fn sample_pixels(img: DynamicImage, width: u32, square: bool) -> anyhow::Result<(Vec<Lab>, usize)> {
+    let mut img = DynamicImage::ImageRgb8(img.into_rgb8());
+    if square {
+        let side = img.width().min(img.height());
+        img = img.crop_imm((img.width() - side) / 2, (img.height() - side) / 2, side, side);
+    }
+
+    let dst_width = width.max(1);
+    let (dst_width, bytes) = if img.width() <= dst_width {
+        (img.width(), img.into_rgb8().into_raw())
+    } else {
+        let dst_height =
+            ((dst_width as f32 / img.width() as f32 * img.height() as f32) as u32).max(1);
+        let mut dst_image = Image::new(dst_width, dst_height, PixelType::U8x3);
+        let mut resizer = Resizer::new();
+        resizer.resize(&img, &mut dst_image, None)?;
+        (dst_width, dst_image.into_vec())
+    };
+
+    let pixels = bytes
+        .chunks_exact(3)
+        .map(|p| {
+            let rgb = Srgb::<u8>::new(p[0], p[1], p[2]);
+            rgb.into_format().into_color()
+        })
+        .collect();
+    Ok((pixels, dst_width as usize))
+}
+
+/// Runs k-means `kmeans_runs` times with consecutive seeds and keeps the best
+/// run. Ties go to the earliest run, so the result doesn't depend on whether
+/// the runs were done in parallel.
+fn run_kmeans(k: usize, img: &[Lab], bg_config: &MyConfig) -> Kmeans<Lab> {
+    let runs = bg_config.kmeans_runs.max(1);
+    let seed = bg_config.random_seed;
+    let (max_iter, convergence) = (bg_config.kmeans_max_iter(), bg_config.kmeans_convergence());
+    let run =
+        |i: usize| get_kmeans(k, max_iter, convergence, false, img, seed.wrapping_add(i as u64));
+    let results: Vec<Kmeans<Lab>> = if bg_config.kmeans_parallel && runs > 1 {
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..runs).map(|i| scope.spawn(move || run(i))).collect();
+            handles.into_iter().map(|h| h.join().expect("k-means run panicked")).collect()
+        })
+    } else {
+        (0..runs).map(run).collect()
+    };
+
+    let mut best_result = Kmeans::new();
+    for run_result in results {
+        if run_result.score < best_result.score {
+            best_result = run_result;
+        }
+    }
+    best_result
+}
+
+/// Picks the number of clusters with the elbow method, returning it with the
+/// clustering of the wallpaper into that many clusters.
+///
+/// The previous choice for the wallpaper, or for the last wallpaper if there is
+/// none, is kept unless the elbow favors another count by more than `margin`,
+/// so that similar images don't flip between adjacent counts. The choice is
+/// only saved with `save`.
+fn auto_cluster_count(
+    img: &[Lab],
+    state_config: Option<&Config>,
+    clusters_p: &str,
+    bg_config: &MyConfig,
+    save: bool,
+) -> (usize, Kmeans<Lab>) {
+    let margin = bg_config.auto_clusters_margin;
+    let runs: Vec<(usize, Kmeans<Lab>)> =
+        AUTO_CLUSTERS.map(|k| (k, run_kmeans(k, img, bg_config))).collect();
+    let scores: Vec<(usize, f32)> = runs.iter().map(|(k, kmeans)| (*k, kmeans.score)).collect();
+    let strengths = elbow_strengths(&scores);
+    let Some(&(best_k, best_strength)) = strengths.iter().max_by(|a, b| a.1.total_cmp(&b.1)) else {
+        return (DEFAULT_CLUSTERS, run_kmeans(DEFAULT_CLUSTERS, img, bg_config));
+    };
+
+    let prev_k = state_config.and_then(|c| {
+        c.get::<usize>(clusters_p).or_else(|_| c.get::<usize>(LAST_CLUSTERS_KEY)).ok()
+    });
+    let k = prev_k
+        .and_then(|prev_k| strengths.iter().find(|(k, _)| *k == prev_k))
+        .filter(|(_, prev_strength)| best_strength - prev_strength <= margin)
+        .map_or(best_k, |(k, _)| *k);
+
+    if let Some(c) = state_config.filter(|_| save) {
+        for key in [clusters_p, LAST_CLUSTERS_KEY] {
+            if let Err(err) = c.set(key, k) {
+                tracing::error!("Failed to save the cluster count: {}", err);
+            }
+        }
+    }
+    let kmeans = runs.into_iter().find(|(run_k, _)| *run_k == k).map(|(_, kmeans)| kmeans);
+    (k, kmeans.unwrap_or_else(|| run_kmeans(k, img, bg_config)))
 }
 
-fn sync_chroma_lightness(target: impl IntoColor<Lch>, c: impl IntoColor<Lch>) -> Srgba {
+/// Adds the centroids of the wallpaper to the recent palettes, keeping the last
+/// `window` wallpapers, and returns their combined centroids. The palettes are
+/// only saved with `save`.
+///
+/// The centroids are interleaved by rank, most recent wallpaper first, so that
+/// the dominant colors of every wallpaper come before the minor ones.
+fn rolling_average(
+    state_config: Option<&Config>,
+    key: &str,
+    path: &std::path::Path,
+    res: Vec<Lab>,
+    window: usize,
+    save: bool,
+) -> Vec<Lab> {
+    let Some(state_config) = state_config else {
+        return res;
+    };
+    // a stable symlink repointed by a rotation tool is a new wallpaper
+    let path = cache::path_key(path);
+    let mut recent: Vec<(String, Vec<Lab>)> = state_config.get(key).unwrap_or_default();
+    recent.retain(|(p, _)| *p != path);
+    recent.push((path, res));
+    let excess = recent.len().saturating_sub(window);
+    recent.drain(..excess);
+    if save {
+        if let Err(err) = state_config.set(key, &recent) {
+            tracing::error!("Failed to save the recent palettes: {}", err);
+        }
+    }
+
+    let longest = recent.iter().map(|(_, r)| r.len()).max().unwrap_or_default();
+    (0..longest)
+        .flat_map(|i| recent.iter().rev().filter_map(move |(_, r)| r.get(i).copied()))
+        .collect()
+}
+
+/// Normalized distance of each point of the score curve from the line joining
+/// its ends. The knee of the curve is the point furthest from the line.
+fn elbow_strengths(scores: &[(usize, f32)]) -> Vec<(usize, f32)> {
+    let (Some(first), Some(last)) = (scores.first(), scores.last()) else {
+        return Vec::new();
+    };
+    let x_range = (last.0 - first.0).max(1) as f32;
+    let y_range = (first.1 - last.1).abs().max(f32::EPSILON);
+    scores
+        .iter()
+        .map(|(k, score)| {
+            let x = (k - first.0) as f32 / x_range;
+            let y = (first.1 - score) / y_range;
+            // distance from the line y = x
+            (*k, (y - x) / std::f32::consts::SQRT_2)
+        })
+        .collect()
+}
+
+/// The value at `t` from 0 to 1 along `range`
+fn lerp(range: std::ops::RangeInclusive<f32>, t: f32) -> f32 {
+    range.start() + (range.end() - range.start()) * t
+}
+
+/// `c` with the chroma and lightness of `target`, gamut mapped with `fit`
+fn sync_chroma_lightness(target: impl IntoColor<Lch>, c: impl IntoColor<Lch>, fit: bool) -> Srgba {
     let target = target.into_color();
     let mut c = c.into_color();
     c.chroma = target.chroma;
     c.l = target.l;
-    c.clamp().into_color()
+    fit_gamut(c.clamp(), fit).into_color()
+}
+
+/// `c` with the lightness of `target`, keeping its hue and chroma, gamut
+/// mapped with `fit`
+fn sync_lightness(target: impl IntoColor<Lch>, c: impl IntoColor<Lch>, fit: bool) -> Srgba {
+    let target: Lch = target.into_color();
+    let mut c: Lch = c.into_color();
+    c.l = target.l;
+    fit_gamut(c.clamp(), fit).into_color()
+}
+
+/// With `fit`, lowers the chroma of `c` until it is in the sRGB gamut, so that
+/// the conversion to sRGB keeps its hue and lightness. Without it the
+/// conversion clamps each channel, which can shift the hue of vivid colors.
+fn fit_gamut(c: Lch, fit: bool) -> Lch {
+    if !fit {
+        return c;
+    }
+    Lch::new(c.l, max_chroma_in_gamut(c.l, c.hue, c.chroma), c.hue)
+}
+
+/// The Euclidean distance of two colors in Lab
+fn lab_distance(a: Lab, b: Lab) -> f32 {
+    ((a.l - b.l).powi(2) + (a.a - b.a).powi(2) + (a.b - b.b).powi(2)).sqrt()
+}
+
+/// Merges the centroids closer than `max_distance` in Lab to a more common
+/// one into it, summing their percentages.
+///
+/// K-means can split one region of the wallpaper over several nearly identical
+/// centroids, which would each count as less prominent than the region is.
+/// The merged centroid keeps the color of the most common one, and is indexed
+/// by its position in the merged list. The cluster of each pixel in `indices`
+/// is returned remapped to these indices.
+fn merge_similar_centroids(
+    mut centroids: Vec<CentroidData<Lab>>,
+    indices: &[u8],
+    max_distance: f32,
+) -> (Vec<CentroidData<Lab>>, Vec<u8>) {
+    centroids.sort_by(|a, b| b.percentage.total_cmp(&a.percentage));
+    let mut merged: Vec<CentroidData<Lab>> = Vec::with_capacity(centroids.len());
+    // the merged index of each original index
+    let mut remap = [0_u8; 256];
+    for c in centroids {
+        match merged.iter_mut().find(|m| lab_distance(m.centroid, c.centroid) < max_distance) {
+            Some(m) => {
+                m.percentage += c.percentage;
+                remap[c.index as usize] = m.index;
+            },
+            None => {
+                let index = merged.len() as u8;
+                remap[c.index as usize] = index;
+                merged.push(CentroidData { index, ..c });
+            },
+        }
+    }
+    (merged, indices.iter().map(|i| remap[*i as usize]).collect())
+}
+
+/// Whether `c` falls inside the tolerance region of any of the `avoid` colors.
+fn is_near_avoid(c: Lch, avoid: &[Srgb], tolerance: &AvoidTolerance) -> bool {
+    avoid.iter().any(|a| {
+        let a = Lch::from_color(*a);
+        let hue_diff = (c.hue.into_inner() - a.hue.into_inner()).abs() % 180.;
+        (c.chroma - a.chroma).powf(2.) + hue_diff.powf(2.) < tolerance.max_combined_sq
+            && hue_diff < tolerance.max_hue_deg
+    })
+}
+
+fn sync_palette_color(
+    accent: impl IntoColor<Lch>,
+    c: impl IntoColor<Lch>,
+    harmony: PaletteHarmony,
+    fit: bool,
+) -> Srgba {
+    let accent: Lch = accent.into_color();
+    let mut c: Lch = c.into_color();
+    c.hue = harmony.place_hue(accent.hue, c.hue);
+    sync_chroma_lightness(accent, c, fit)
+}
+
+/// Picks the window background from the first candidate that isn't avoided,
+/// with its chroma kept within `bg_chroma_delta` of `default_window_bg` and
+/// its lightness.
+///
+/// The candidates with a hue close to the background's are removed from `res`,
+/// as they wouldn't stand out from it.
+fn pick_bg(
+    res: &mut Vec<Lab>,
+    default_window_bg: Lch,
+    avoid_bg: &[Srgb],
+    bg_config: &MyConfig,
+) -> Option<Lch> {
+    let max_delta = bg_config.bg_chroma_delta.max(0.);
+    let mut bg = None;
+    for c in res.iter() {
+        let mut new_window_bg: Lch = (*c).into_color();
+        // in strict mode the centroid itself must not be avoided either, not
+        // only its chroma-capped version
+        if bg_config.strict_avoid_bg
+            && is_near_avoid(new_window_bg, avoid_bg, &bg_config.avoid_tolerance)
+        {
+            continue;
+        }
+
+        // make sure not in avoid after adjusting
+        if (new_window_bg.chroma - default_window_bg.chroma).abs() > max_delta {
+            new_window_bg.chroma = default_window_bg.chroma + max_delta;
+            new_window_bg = new_window_bg.clamp();
+        }
+        if is_near_avoid(new_window_bg, avoid_bg, &bg_config.avoid_tolerance) {
+            continue;
+        }
+
+        new_window_bg.l = default_window_bg.l;
+        bg = Some(new_window_bg);
+        break;
+    }
+
+    // grey has no hue to tell the colors apart by
+    if let Some(bg) = bg.filter(|_| !bg_config.monochrome) {
+        res.retain(|c| {
+            let c = Lch::from_color(*c);
+            (c.hue - bg.hue).into_inner().abs() > 10.
+        });
+    }
+    bg
+}
+
+/// The centroid with the largest share of the image, as it appears among the
+/// candidates.
+fn dominant_color(shares: &[(Lab, f32)], bg_config: &MyConfig) -> Option<Lab> {
+    let (c, _) = shares.iter().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    Some(if bg_config.monochrome { Lab::new(c.l, 0., 0.) } else { *c })
+}
+
+/// The accent candidates, without `dominant` with `skip_dominant_for_accent`.
+/// The candidates are reordered and the background may already have taken
+/// the dominant color, so it is removed by value.
+fn accent_pool(res: &[Lab], dominant: Option<Lab>, bg_config: &MyConfig) -> Vec<Lab> {
+    match dominant {
+        Some(dominant) if bg_config.skip_dominant_for_accent && res.len() > 2 => {
+            res.iter().copied().filter(|c| *c != dominant).collect()
+        },
+        _ => res.to_vec(),
+    }
+}
+
+/// The number of candidates, from the most common, in which a vivid color is
+/// accepted as accent right away. As an `f32`, the default of a third is
+/// slightly above it, so it floors to `len / 3` even for multiples of 3.
+fn early_accept_len(len: usize, fraction: f32) -> usize {
+    (len as f32 * fraction.clamp(0., 1.)) as usize
+}
+
+/// Scores the accent candidates, returning the chosen centroid and its
+/// contrast-adjusted color. Vivid candidates among the first `early_accept_len`
+/// are accepted directly. Candidates near `consistent_hue` score higher.
+/// Returns `None` if there are no candidates.
+///
+/// The score weighs the chroma of a candidate against its share of the image,
+/// looked up in `shares`, and its CIEDE2000 distance from `bg`. Candidates
+/// without a share, such as the averaged colors of other wallpapers, count as
+/// 0%.
+fn pick_accent(
+    candidates: &[Lab],
+    shares: &[(Lab, f32)],
+    early_accept_len: usize,
+    bg: Lch,
+    avoid: &[Srgb],
+    consistent_hue: Option<LabHue>,
+    bg_config: &MyConfig,
+) -> Option<(Lab, Lch)> {
+    let first = *candidates.first()?;
+    let mut accent: (Lab, Lch) = (first, first.into_color());
+    let mut best = f32::MIN;
+    for (i, color) in candidates.iter().enumerate() {
+        let lch_orig = Lch::from_color(*color);
+        let adjusted = adjust_lightness_for_contrast(
+            (*color).into_color(),
+            bg,
+            bg_config.contrast_model,
+            bg_config.min_contrast(),
+            bg_config.contrast_search_steps,
+        );
+        let share = shares.iter().find(|(c, _)| c == color).map_or(0., |(_, share)| *share);
+        let mut score = bg_config.accent_chroma_weight * adjusted.chroma
+            + bg_config.accent_percentage_weight * share * 100.
+            + bg_config.accent_distinctiveness_weight * adjusted.difference(bg);
+        let preferred = bg_config.hue_preference.matches(adjusted.hue);
+        if preferred && bg_config.hue_preference != HuePreference::None {
+            score *= HUE_PREFERENCE_BONUS;
+        }
+        if consistent_hue
+            .is_some_and(|hue| (adjusted.hue - hue).into_inner().abs() < CONSISTENT_HUE_RANGE)
+        {
+            score *= CONSISTENT_HUE_BONUS;
+        }
+        if is_near_avoid(adjusted, avoid, &bg_config.avoid_tolerance) {
+            score /= 10.;
+        } else if preferred && lch_orig.chroma > 60. && i <= early_accept_len {
+            accent = (*color, adjusted);
+            break;
+        }
+        if score > best {
+            best = score;
+            accent = (*color, adjusted);
+        }
+    }
+    Some(accent)
+}
+
+/// `neutral` with its chroma capped by `neutral_max_chroma_dark` or
+/// `neutral_max_chroma_light`, for the mode. Every neutral that is written
+/// goes through here, whether it was derived, saved or a fallback.
+fn cap_neutral_chroma(neutral: impl IntoColor<Lch>, is_dark: bool, bg_config: &MyConfig) -> Lch {
+    let mut neutral = neutral.into_color();
+    let cap = if is_dark {
+        bg_config.neutral_max_chroma_dark
+    } else {
+        bg_config.neutral_max_chroma_light
+    };
+    if let Some(cap) = cap {
+        neutral.chroma = neutral.chroma.min(cap);
+    }
+    neutral
+}
+
+/// Returns `fallback` instead of the accent if even the contrast-adjusted
+/// accent is unreadable on `bg`.
+fn usable_accent_or(
+    accent: Lch,
+    bg: Lch,
+    model: ContrastModel,
+    min_usable_contrast: f32,
+    fallback: Lch,
+) -> Lch {
+    let contrast = model.contrast(accent, bg);
+    if contrast < min_usable_contrast {
+        tracing::warn!(
+            "Rejecting the wallpaper accent with contrast {:.2} below {:.2}, using the fallback",
+            contrast,
+            min_usable_contrast
+        );
+        fallback
+    } else {
+        accent
+    }
+}
+
+/// Runs the picked accent through the checks and adjustments every accent gets,
+/// falling back to `fallback` when it is unreadable on `bg`.
+///
+/// The accent is gamut mapped first, if `gamut_map` is set, so that the
+/// contrast is checked on the color that ends up in the theme.
+fn finish_accent(accent: Lch, bg: Lch, fallback: Lch, is_dark: bool, bg_config: &MyConfig) -> Lch {
+    let mut accent = usable_accent_or(
+        fit_gamut(accent, bg_config.gamut_map),
+        bg,
+        bg_config.contrast_model,
+        bg_config.min_usable_contrast(),
+        fallback,
+    );
+    if accent.chroma < bg_config.accent_min_final_chroma {
+        accent = boost_chroma(
+            accent,
+            bg_config.accent_min_final_chroma,
+            bg,
+            bg_config.contrast_model,
+            bg_config.min_usable_contrast(),
+        )
+        .unwrap_or_else(|| {
+            tracing::warn!("The accent can't reach the minimum chroma, using the fallback");
+            fallback
+        });
+    }
+    let lightness_bias = if is_dark {
+        bg_config.accent_lightness_bias_dark
+    } else {
+        bg_config.accent_lightness_bias_light
+    };
+    if lightness_bias != 0. {
+        accent = bias_lightness(
+            accent,
+            lightness_bias,
+            bg,
+            bg_config.contrast_model,
+            bg_config.min_contrast(),
+        );
+    }
+    if bg_config.monochrome {
+        // the fallback accents are colorful, and the palette follows the accent
+        accent.chroma = 0.;
+    }
+    accent
+}
+
+/// Whether the accent and text tint of a generated theme still reach
+/// `min_contrast` on `bg`. A theme without them wasn't generated, so it never
+/// counts as readable.
+fn still_readable(
+    accent: Option<Srgb>,
+    text: Option<Srgb>,
+    bg: Lch,
+    model: ContrastModel,
+    min_contrast: f32,
+) -> bool {
+    let (Some(accent), Some(text)) = (accent, text) else {
+        return false;
+    };
+    [accent, text].into_iter().all(|c| model.contrast(c.into_color(), bg) >= min_contrast)
 }
 
-// binary search modifying a's lightness to satisfy contrast with b
-fn adjust_lightness_for_contrast(original: Lch, b: Lch, cutoff: f32) -> Lch {
-    let a_luma = SrgbLuma::from_color(original);
-    let b_luma = SrgbLuma::from_color(b);
+/// How confidently a good theme can be derived from the centroids, from 0 to 1.
+///
+/// The mean of how far the chroma of the centroids spreads, how many distinct
+/// hues they cover and how close the accent gets to the target contrast.
+fn themeability(centroids: &[Lab], accent: Lch, bg: Lch, bg_config: &MyConfig) -> f32 {
+    let lch: Vec<Lch> = centroids.iter().map(|c| Lch::from_color(*c)).collect();
+    let (min_chroma, max_chroma) =
+        lch.iter().fold((f32::MAX, 0_f32), |(min, max), c| (min.min(c.chroma), max.max(c.chroma)));
+    let chroma_spread = ((max_chroma - min_chroma.min(max_chroma)) / 60.).clamp(0., 1.);
+
+    // 30 degree hue bands of the colorful centroids
+    let mut bands = [false; 12];
+    for c in lch.iter().filter(|c| c.chroma > 10.) {
+        bands[(c.hue.into_positive_degrees() / 30.) as usize % 12] = true;
+    }
+    let hues = (bands.iter().filter(|b| **b).count() as f32 / 4.).min(1.);
+
+    let contrast =
+        bg_config.contrast_model.contrast(accent, bg) / bg_config.min_contrast().max(f32::EPSILON);
+    let contrast = contrast.clamp(0., 1.);
 
-    if a_luma.has_min_contrast_text(b_luma) {
+    (chroma_spread + hues + contrast) / 3.
+}
+
+/// Raises the chroma of the accent to `min_chroma`, if the result is still in
+/// the sRGB gamut and readable on `bg`.
+fn boost_chroma(
+    accent: Lch,
+    min_chroma: f32,
+    bg: Lch,
+    model: ContrastModel,
+    min_usable_contrast: f32,
+) -> Option<Lch> {
+    let boosted = Lch::new(accent.l, min_chroma, accent.hue);
+    let in_gamut = Srgb::from_color_unclamped(boosted).is_within_bounds();
+    (in_gamut && model.contrast(boosted, bg) >= min_usable_contrast).then_some(boosted)
+}
+
+/// Shifts the lightness of the accent by `bias`, or as far towards it as the
+/// accent stays in the sRGB gamut and reaches `min_contrast` on `bg`.
+fn bias_lightness(accent: Lch, bias: f32, bg: Lch, model: ContrastModel, min_contrast: f32) -> Lch {
+    (1..=LIGHTNESS_BIAS_STEPS)
+        .rev()
+        .map(|i| {
+            let mut c = accent;
+            c.l = (accent.l + bias * i as f32 / LIGHTNESS_BIAS_STEPS as f32).clamp(0., 100.);
+            c
+        })
+        .find(|c| {
+            Srgb::from_color_unclamped(*c).is_within_bounds()
+                && model.contrast(*c, bg) >= min_contrast
+        })
+        .unwrap_or(accent)
+}
+
+// search a grid of `steps` lightness values for the one closest to a's
+// lightness that satisfies contrast with b
+fn adjust_lightness_for_contrast(
+    original: Lch,
+    b: Lch,
+    model: ContrastModel,
+    cutoff: f32,
+    steps: usize,
+) -> Lch {
+    if model.contrast(original, b) >= cutoff {
         return original;
     }
 
-    let c_arr: Vec<(Lch, f32)> = (0..=40)
+    let steps = steps.max(MIN_CONTRAST_SEARCH_STEPS);
+    let c_arr: Vec<(Lch, f32)> = (0..=steps)
         .map(|i| {
             let mut c = original;
-            c.l = 100. * i as f32 / 40.;
+            c.l = 100. * i as f32 / steps as f32;
             c.clamp()
         })
-        .map(|c| {
-            let c_luma = SrgbLuma::from_color(c);
-            let contrast = c_luma.relative_contrast(b_luma);
-            (c, contrast)
-        })
+        .map(|c| (c, model.contrast(c, b)))
         .collect();
     let filtered = c_arr.iter().filter(|c| c.1 > cutoff).cloned().collect::<Vec<(Lch, f32)>>();
     filtered
@@ -560,10 +2153,45 @@ fn adjust_lightness_for_contrast(original: Lch, b: Lch, cutoff: f32) -> Lch {
         })
 }
 
-fn use_saved_result(path: &str, is_dark: bool) -> anyhow::Result<()> {
-    let my_config = cosmic_config::Config::new_state(ID, 1)?;
+fn use_saved_result(
+    path: &str,
+    is_dark: bool,
+    bg_config: &MyConfig,
+) -> anyhow::Result<GeneratedTheme> {
+    let my_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)?;
     let result = my_config.get::<BgResult>(path)?;
+    theme_from_result(result, is_dark, bg_config)
+}
+
+/// Saves the result applied in the mode for `apply_last_on_startup`.
+fn save_last_result(result: &BgResult, is_dark: bool) {
+    let saved = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)
+        .and_then(|c| c.set(cache::last_result_key(is_dark), result));
+    if let Err(err) = saved {
+        tracing::error!("Failed to save the last result: {}", err);
+    }
+}
 
+/// The theme configured with `fallback_accent` and `fallback_neutral` on the
+/// default background, if a fallback accent is configured.
+fn fallback_theme(bg_config: &MyConfig, is_dark: bool) -> Option<anyhow::Result<GeneratedTheme>> {
+    let accent = bg_config.fallback_accent?;
+    let default = if is_dark { Theme::dark_default() } else { Theme::light_default() };
+    let result = BgResult {
+        accent,
+        bg: default.background.base,
+        neutral: bg_config.fallback_neutral.unwrap_or(default.palette.neutral_5.into_color()),
+        text: None,
+    };
+    Some(theme_from_result(result, is_dark, bg_config))
+}
+
+/// Applies the colors of `result` to the current theme builder of the mode.
+fn theme_from_result(
+    result: BgResult,
+    is_dark: bool,
+    bg_config: &MyConfig,
+) -> anyhow::Result<GeneratedTheme> {
     let builder_config =
         if is_dark { ThemeBuilder::dark_config()? } else { ThemeBuilder::light_config()? };
 
@@ -577,21 +2205,35 @@ fn use_saved_result(path: &str, is_dark: bool) -> anyhow::Result<()> {
         },
     };
 
-    t = t.accent(result.accent).bg_color(result.bg.into_color()).neutral_tint(result.neutral);
+    let current = t.clone();
+    // a result saved before the cap was set can be more colorful
+    let neutral = Srgb::from_color(cap_neutral_chroma(result.neutral, is_dark, bg_config));
+    let result = BgResult { neutral, ..result };
+
+    t = t.accent(result.accent).bg_color(result.bg.into_color());
+    if bg_config.derive_neutral {
+        t = t.neutral_tint(result.neutral);
+    } else {
+        t.neutral_tint = None;
+    }
 
     if let Some(text) = result.text {
         t = t.text_tint(text);
     }
+    let default = if is_dark { Theme::dark_default() } else { Theme::light_default() };
+    bg_config.tint_scope.apply(&mut t, &default);
+    bg_config.write_fields.keep_disabled(&mut t, &current);
 
-    t.write_entry(&builder_config)?;
-
-    let theme = t.build();
-
-    let theme_config = if theme.is_dark { Theme::dark_config() } else { Theme::light_config() }?;
-
-    theme.write_entry(&theme_config)?;
+    Ok(GeneratedTheme { builder: t, result, accent_candidates: Vec::new() })
+}
 
-    Ok(())
+/// A theme that has been generated for an output but not written yet
+pub struct GeneratedTheme {
+    pub builder: ThemeBuilder,
+    pub result: BgResult,
+    /// Accents to cycle through with `rotate_accent_secs`, empty if the theme
+    /// wasn't extracted from the wallpaper
+    pub accent_candidates: Vec<Srgb>,
 }
 
 // TODO add palette colors
@@ -603,62 +2245,1122 @@ pub struct BgResult {
     pub text: Option<Srgb>,
 }
 
-/// Sorted colors
+/// Sorted colors, and the share of the image of each, from 0 to 1
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct KmeanState(pub Vec<Lab>);
+pub struct KmeanState(pub Vec<Lab>, pub Vec<f32>);
+
+impl KmeanState {
+    /// Checks that the cached centroids are usable, rejecting empty entries
+    /// and entries with non-finite or out-of-range components, which can be
+    /// left behind by a bad write or an older schema.
+    pub fn is_valid(&self) -> bool {
+        !self.0.is_empty()
+            && self.0.len() == self.1.len()
+            && self.1.iter().all(|p| (0. ..=1.).contains(p))
+            && self.0.iter().all(|c| {
+                c.l.is_finite()
+                    && c.a.is_finite()
+                    && c.b.is_finite()
+                    && (0. ..=100.).contains(&c.l)
+                    && (-128. ..=128.).contains(&c.a)
+                    && (-128. ..=128.).contains(&c.b)
+            })
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, CosmicConfigEntry)]
 #[version = 1]
+#[serde(default)]
 pub struct MyConfig {
-    pub avoid_accents_light: Vec<Srgb>,
-    pub avoid_accents_dark: Vec<Srgb>,
-    pub avoid_light: Vec<Srgb>,
-    pub avoid_dark: Vec<Srgb>,
+    pub avoid_accents_light: Vec<AvoidColor>,
+    pub avoid_accents_dark: Vec<AvoidColor>,
+    pub avoid_light: Vec<AvoidColor>,
+    pub avoid_dark: Vec<AvoidColor>,
     pub save_results: bool,
     pub save_kmeans: bool,
     pub randomize: bool,
+    pub palette_harmony: PaletteHarmony,
+    pub auto_clusters: bool,
+    /// How much more pronounced a new elbow must be than the previous cluster
+    /// count before switching to it
+    pub auto_clusters_margin: f32,
+    pub avoid_tolerance: AvoidTolerance,
+    /// Sigma of the Gaussian blur applied to the wallpaper before extraction.
+    /// Disabled when 0.
+    pub pre_blur_sigma: f32,
+    pub hue_preference: HuePreference,
+    /// Number of lightness steps searched when adjusting the accent for
+    /// contrast. Values below 4 are raised to 4.
+    pub contrast_search_steps: usize,
+    /// Upper bound for the chroma of the derived neutral
+    pub neutral_max_chroma: f32,
+    /// How often a video wallpaper is re-sampled, in seconds. Disabled when 0.
+    pub video_refresh_secs: u64,
+    /// Command grabbing a frame of a video wallpaper, see [`video::grab_frame`]
+    pub video_frame_cmd: Option<String>,
+    /// Exclude the most common color from the accent candidates
+    pub skip_dominant_for_accent: bool,
+    /// Where to write the colors of the active theme in pywal's `colors.json`
+    /// format
+    pub pywal_path: Option<PathBuf>,
+    /// Delay before the first theme is applied on startup
+    pub startup_delay_ms: u64,
+    /// Width the wallpaper is downscaled to before extraction
+    pub sample_width: u32,
+    /// Center-crop the wallpaper to a square before downscaling
+    pub square_sample: bool,
+    /// Contrast with the background below which the accent is replaced by the
+    /// default accent, in the units of `contrast_model`. Defaults to the
+    /// model's default when unset.
+    pub min_usable_contrast: Option<f32>,
+    /// Derive the light theme from the extraction and accent hue of the dark
+    /// theme
+    pub link_modes: bool,
+    pub contrast_model: ContrastModel,
+    /// Contrast the accent is adjusted to reach against the background, in the
+    /// units of `contrast_model`. Defaults to the model's default when unset.
+    pub min_contrast: Option<f32>,
+    /// Fraction of the candidates, from the most common, in which a vivid
+    /// color is accepted as accent right away
+    pub accent_early_accept_fraction: f32,
+    /// Save a swatch strip of each saved result, for previews
+    pub save_thumbnails: bool,
+    /// How far the chroma of the window background may exceed the default
+    pub bg_chroma_delta: f32,
+    /// Log the approximate names of the chosen colors next to their hex
+    pub name_colors_in_log: bool,
+    /// Reject background candidates that are avoided before their chroma is
+    /// capped, not only after
+    pub strict_avoid_bg: bool,
+    /// Number of k-means restarts, the best of which is kept
+    pub kmeans_runs: usize,
+    /// Seed of the first k-means restart
+    pub random_seed: u64,
+    /// Run the k-means restarts on separate threads
+    pub kmeans_parallel: bool,
+    /// Accent applied when the wallpaper can't be themed or every accent
+    /// candidate is avoided, instead of keeping the previous theme
+    pub fallback_accent: Option<Srgb>,
+    /// Neutral tint applied with `fallback_accent`, and when the wallpaper has
+    /// no colorful enough neutral
+    pub fallback_neutral: Option<Srgb>,
+    /// Order the colors by their largest contiguous area in the wallpaper
+    /// rather than by their total area
+    pub region_weighting: bool,
+    /// Hold back theme changes while `fullscreen_cmd` reports a fullscreen
+    /// window, applying the latest one once it is gone
+    pub defer_while_fullscreen: bool,
+    /// Command run with `sh -c` that exits successfully while a window is
+    /// fullscreen
+    pub fullscreen_cmd: Option<String>,
+    /// Maximum number of iterations of each k-means run
+    pub kmeans_max_iter: usize,
+    /// Score change below which a k-means run is considered converged
+    pub kmeans_convergence: f32,
+    /// Command run with `sh -c` after each theme is written, with the colors in
+    /// `$ACCENT`, `$BG`, `$NEUTRAL` and `$TEXT` and the mode in `$IS_DARK`
+    pub post_apply_cmd: Option<String>,
+    /// Accent colors avoided in both modes, in addition to
+    /// `avoid_accents_dark` and `avoid_accents_light`
+    pub avoid_accents_common: Vec<AvoidColor>,
+    /// Background colors avoided in both modes, in addition to `avoid_dark`
+    /// and `avoid_light`
+    pub avoid_common: Vec<AvoidColor>,
+    /// Convert wallpapers with an embedded ICC profile to sRGB before sampling.
+    /// Requires the `icc` feature.
+    pub use_icc_profile: bool,
+    /// Chroma the final accent is raised to if it is less colorful. The
+    /// fallback accent is used if that leaves the gamut or hurts contrast.
+    pub accent_min_final_chroma: f32,
+    /// Where to write the colors of the active theme as GTK named colors
+    pub gtk_css_path: Option<PathBuf>,
+    /// Tint the neutral colors with the wallpaper, or keep the default grey
+    pub derive_neutral: bool,
+    /// How many times a failed theme or cache write is retried
+    pub config_write_retries: u32,
+    /// Generate a grayscale theme that only follows the lightness of the
+    /// wallpaper
+    pub monochrome: bool,
+    /// Keep the current window background and only derive the other colors
+    pub preserve_bg: bool,
+    /// Cluster only the part of the wallpaper with the most color variation
+    pub entropy_crop: bool,
+    /// Which parts of the theme the daemon may overwrite
+    pub write_fields: WriteFields,
+    /// Which of several wallpapers that changed at once to theme from
+    pub multi_change: MultiChange,
+    /// Name of the output whose wallpaper drives the theme. It is preferred
+    /// over the first output when nothing or several wallpapers changed, and
+    /// always used with `multi_change: Primary`
+    pub primary_output: Option<String>,
+    /// Lab distance below which the accent is considered too close to the
+    /// neutral and picked again
+    pub min_accent_neutral_distance: f32,
+    /// Derive the theme from the combined colors of this many recent
+    /// wallpapers, to smooth out slideshows
+    pub rolling_average_window: usize,
+    /// Which surfaces are tinted with the wallpaper colors
+    pub tint_scope: TintScope,
+    /// Named sRGB colors, as `[red, green, blue]` in 0 to 1, that can be used
+    /// in place of a color in the avoid lists
+    pub color_aliases: BTreeMap<String, [f32; 3]>,
+    /// Keep the current theme on a wallpaper change while its accent and text
+    /// still reach the minimum contrast on the new background
+    pub lazy_update: bool,
+    /// Cycle the accent through the top accent candidates of the last
+    /// extraction every this many seconds, without extracting again
+    pub rotate_accent_secs: Option<u64>,
+    /// Added to the lightness of the dark theme accent, as far as the contrast
+    /// allows
+    pub accent_lightness_bias_dark: f32,
+    /// Added to the lightness of the light theme accent, as far as the contrast
+    /// allows
+    pub accent_lightness_bias_light: f32,
+    /// How many of the centroids, most common first, are considered for the
+    /// background, accent, neutral and text
+    pub max_candidates: usize,
+    /// Read the wallpapers from the state config of cosmic-bg again right
+    /// before applying, instead of relying on its change signals being current
+    pub live_wallpaper: bool,
+    /// Sample the thumbnail the desktop cached for the wallpaper instead of
+    /// decoding the full image, when one at least `sample_width` wide is
+    /// up to date
+    pub use_desktop_thumbnails: bool,
+    /// Match the semantic palette colors to the accent. Without it the palette
+    /// is left as it is
+    pub harmonize_palette: bool,
+    /// Download wallpapers given as http(s) URLs, with the remote feature
+    pub allow_remote: bool,
+    /// Favor light theme accents near the hue of the dark theme accent, without
+    /// linking them like `link_modes`
+    pub prefer_consistent_accent_hue: bool,
+    /// Commit only the changed theme builder keys, together, and build the
+    /// theme before writing, to narrow the window in which apps can read a
+    /// half-updated theme
+    pub atomic_writes: bool,
+    /// Weight of the chroma of a candidate in the accent score
+    pub accent_chroma_weight: f32,
+    /// Weight of the share of the image of a candidate, in percent, in the
+    /// accent score. Raise it to favor prominent colors over rare vivid ones.
+    pub accent_percentage_weight: f32,
+    /// Keep the hue and chroma of the palette reds when harmonizing the
+    /// palette, so that errors and destructive actions still look red
+    pub preserve_semantic_red: bool,
+    /// On startup, apply the last result of each mode right away, then
+    /// extract the current wallpaper
+    pub apply_last_on_startup: bool,
+    /// Lower the chroma of the harmonized palette to what every palette color
+    /// can reach, instead of letting the gamut wash out only some of them
+    pub equalize_palette_chroma: bool,
+    /// Read wallpapers that can't be opened directly, as in a sandbox, from
+    /// the document portal
+    pub use_document_portal: bool,
+    /// Fit out-of-gamut accent and palette colors into sRGB by lowering their
+    /// chroma, keeping their hue, instead of clamping each channel
+    pub gamut_map: bool,
+    /// Experimental. Cluster only the colors on the strongest edges of the
+    /// wallpaper, drawing the accent from outlines and highlights rather than
+    /// large flat areas. Disables `region_weighting`.
+    pub edge_sampling: bool,
+    pub text_tint_strategy: TextTintStrategy,
+    pub shuffle_skew: ShuffleSkew,
+    /// How bold the theme is, from subtle at 0 to bold at 1. It sets
+    /// `bg_chroma_delta` from 5 to 25, `neutral_max_chroma` from 10 to 30 and,
+    /// above 0.5, `accent_min_final_chroma` up to 60, unless they are changed
+    /// from their defaults. 0.5 keeps the defaults.
+    pub intensity: f32,
+    /// Frames sampled from animated GIF and PNG wallpapers, spread over the
+    /// animation and clustered together. 1 samples only the first frame.
+    pub animated_frames: usize,
+    /// Pick the neutral and the text tint from the centroids before those
+    /// close to the accent hue are dropped, so that they don't fall back to the
+    /// defaults when few centroids are left. They can then share the hue of
+    /// the accent.
+    pub neutral_text_before_hue_spread: bool,
+    /// Weight of the CIEDE2000 distance of a candidate from the background in
+    /// the accent score, to favor accents that stand out from it
+    pub accent_distinctiveness_weight: f32,
+    /// Restore the previous theme if writing the new one fails partway
+    pub rollback_on_failure: bool,
+    /// Config overrides for the wallpapers under a directory, for example
+    /// other avoid lists for a folder of dark wallpapers
+    pub directory_profiles: Vec<DirectoryProfile>,
+    /// Upper bound for the chroma of the light theme neutral, whichever way it
+    /// was picked. Light UIs look tinted with less chroma than dark ones.
+    pub neutral_max_chroma_light: Option<f32>,
+    /// Upper bound for the chroma of the dark theme neutral, whichever way it
+    /// was picked
+    pub neutral_max_chroma_dark: Option<f32>,
+    /// Merge the centroids closer than `merge_distance` to a more common one
+    /// into it, before the candidates are ordered by how common they are
+    pub merge_similar_centroids: bool,
+    /// Lab distance below which `merge_similar_centroids` merges two centroids
+    pub merge_distance: f32,
 }
 
 impl Default for MyConfig {
     fn default() -> Self {
         Self {
             avoid_accents_light: vec![
-                Srgb::new(0.54, 0.38, 0.28),
-                Srgb::new(0.47, 0.34, 0.14),
-                Srgb::new(0.56078, 0.40784, 0.17647),
-                Srgb::new(0.56078, 0.40784, 0.07),
-                Srgb::new(0.651, 0.486, 0.443),
-                Srgb::new(0.439, 0.431, 0.078),
-                Srgb::new(0.47, 0.51, 0.32),
+                Srgb::new(0.54, 0.38, 0.28).into(),
+                Srgb::new(0.47, 0.34, 0.14).into(),
+                Srgb::new(0.56078, 0.40784, 0.17647).into(),
+                Srgb::new(0.56078, 0.40784, 0.07).into(),
+                Srgb::new(0.651, 0.486, 0.443).into(),
+                Srgb::new(0.439, 0.431, 0.078).into(),
+                Srgb::new(0.47, 0.51, 0.32).into(),
             ],
             avoid_accents_dark: vec![
-                Srgb::new(0.54, 0.38, 0.28),
-                Srgb::new(0.47, 0.34, 0.14),
-                Srgb::new(0.56078, 0.40784, 0.17647),
-                Srgb::new(0.56078, 0.40784, 0.07),
-                Srgb::new(0.651, 0.486, 0.443),
-                Srgb::new(0.439, 0.431, 0.078),
-                Srgb::new(0.47, 0.51, 0.32),
+                Srgb::new(0.54, 0.38, 0.28).into(),
+                Srgb::new(0.47, 0.34, 0.14).into(),
+                Srgb::new(0.56078, 0.40784, 0.17647).into(),
+                Srgb::new(0.56078, 0.40784, 0.07).into(),
+                Srgb::new(0.651, 0.486, 0.443).into(),
+                Srgb::new(0.439, 0.431, 0.078).into(),
+                Srgb::new(0.47, 0.51, 0.32).into(),
             ],
             avoid_light: Vec::new(),
             avoid_dark: vec![
-                Srgb::new(0.169, 0.165, 0.004),
-                Srgb::new(0.169, 0.098, 0.004),
-                Srgb::new(0.29, 0.18, 0.129),
-                Srgb::new(0.29, 0.271, 0.129),
+                Srgb::new(0.169, 0.165, 0.004).into(),
+                Srgb::new(0.169, 0.098, 0.004).into(),
+                Srgb::new(0.29, 0.18, 0.129).into(),
+                Srgb::new(0.29, 0.271, 0.129).into(),
             ],
             save_results: false,
             save_kmeans: true,
             randomize: true,
+            palette_harmony: PaletteHarmony::default(),
+            auto_clusters: false,
+            auto_clusters_margin: 0.05,
+            avoid_tolerance: AvoidTolerance::default(),
+            pre_blur_sigma: 0.,
+            hue_preference: HuePreference::default(),
+            contrast_search_steps: 40,
+            neutral_max_chroma: 20.,
+            video_refresh_secs: 300,
+            video_frame_cmd: None,
+            skip_dominant_for_accent: false,
+            pywal_path: None,
+            startup_delay_ms: 0,
+            sample_width: 256,
+            square_sample: false,
+            min_usable_contrast: None,
+            link_modes: false,
+            contrast_model: ContrastModel::default(),
+            min_contrast: None,
+            accent_early_accept_fraction: 1. / 3.,
+            save_thumbnails: false,
+            bg_chroma_delta: 15.,
+            name_colors_in_log: false,
+            strict_avoid_bg: false,
+            kmeans_runs: 2,
+            random_seed: 42,
+            kmeans_parallel: true,
+            fallback_accent: None,
+            fallback_neutral: None,
+            region_weighting: false,
+            defer_while_fullscreen: false,
+            fullscreen_cmd: None,
+            kmeans_max_iter: 40,
+            kmeans_convergence: 10.,
+            post_apply_cmd: None,
+            avoid_accents_common: Vec::new(),
+            avoid_common: Vec::new(),
+            use_icc_profile: true,
+            accent_min_final_chroma: 0.,
+            gtk_css_path: None,
+            derive_neutral: true,
+            config_write_retries: 3,
+            monochrome: false,
+            preserve_bg: false,
+            entropy_crop: false,
+            write_fields: WriteFields::default(),
+            multi_change: MultiChange::default(),
+            primary_output: None,
+            min_accent_neutral_distance: 0.,
+            rolling_average_window: 1,
+            tint_scope: TintScope::default(),
+            color_aliases: BTreeMap::new(),
+            lazy_update: false,
+            rotate_accent_secs: None,
+            accent_lightness_bias_dark: 0.,
+            accent_lightness_bias_light: 0.,
+            max_candidates: *AUTO_CLUSTERS.end(),
+            live_wallpaper: false,
+            use_desktop_thumbnails: false,
+            harmonize_palette: true,
+            allow_remote: false,
+            prefer_consistent_accent_hue: false,
+            atomic_writes: false,
+            accent_chroma_weight: 1.,
+            accent_percentage_weight: 0.,
+            preserve_semantic_red: false,
+            apply_last_on_startup: false,
+            equalize_palette_chroma: false,
+            use_document_portal: false,
+            gamut_map: false,
+            edge_sampling: false,
+            text_tint_strategy: TextTintStrategy::default(),
+            shuffle_skew: ShuffleSkew::default(),
+            intensity: 0.5,
+            animated_frames: 1,
+            neutral_text_before_hue_spread: false,
+            accent_distinctiveness_weight: 0.,
+            rollback_on_failure: true,
+            directory_profiles: Vec::new(),
+            neutral_max_chroma_light: None,
+            neutral_max_chroma_dark: None,
+            merge_similar_centroids: false,
+            merge_distance: 5.,
+        }
+    }
+}
+
+/// The region around an avoided color in which a candidate is considered to
+/// match it.
+///
+/// In the chroma/hue plane the region is an ellipse: the squared chroma
+/// difference plus the squared hue difference in degrees must be below
+/// `max_combined_sq`. The hue difference alone must also be below
+/// `max_hue_deg`, so that greys of any hue don't all match each other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AvoidTolerance {
+    pub max_combined_sq: f32,
+    pub max_hue_deg: f32,
+}
+
+impl AvoidTolerance {
+    pub const DEFAULT_MAX_COMBINED_SQ: f32 = 666.;
+    pub const DEFAULT_MAX_HUE_DEG: f32 = 20.;
+}
+
+impl Default for AvoidTolerance {
+    fn default() -> Self {
+        Self {
+            max_combined_sq: Self::DEFAULT_MAX_COMBINED_SQ,
+            max_hue_deg: Self::DEFAULT_MAX_HUE_DEG,
+        }
+    }
+}
+
+/// Which of the wallpapers that changed at once the theme is generated from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MultiChange {
+    /// The first changed wallpaper that can be themed
+    #[default]
+    First,
+    /// The wallpaper of `primary_output`, whether it changed or not
+    Primary,
+    /// Every changed wallpaper, of which the first is applied for now
+    All,
+}
+
+/// Which surfaces are tinted with the wallpaper colors
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TintScope {
+    /// Every surface
+    #[default]
+    All,
+    /// Only the window background and headers. Containers and components
+    /// keep the default grey.
+    Primary,
+}
+
+impl TintScope {
+    /// Restricts the tinting of the builder `t` to the scope.
+    pub fn apply(&self, t: &mut ThemeBuilder, default: &Theme) {
+        match self {
+            TintScope::All => {
+                // undo the pinned containers of the primary scope, leaving
+                // ones set by the user alone
+                if t.primary_container_bg == Some(default.primary.base) {
+                    t.primary_container_bg = None;
+                }
+                if t.secondary_container_bg == Some(default.secondary.base) {
+                    t.secondary_container_bg = None;
+                }
+            },
+            TintScope::Primary => {
+                // the containers would otherwise be derived from the tinted
+                // background
+                t.primary_container_bg = Some(default.primary.base);
+                t.secondary_container_bg = Some(default.secondary.base);
+                t.neutral_tint = None;
+            },
+        }
+    }
+}
+
+/// The parts of the theme the daemon may overwrite. Disabled parts keep their
+/// current values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteFields {
+    pub accent: bool,
+    pub bg: bool,
+    pub neutral: bool,
+    pub text: bool,
+    pub palette: bool,
+}
+
+impl WriteFields {
+    /// Restores the disabled parts of the generated builder `t` from the
+    /// `current` builder.
+    pub fn keep_disabled(&self, t: &mut ThemeBuilder, current: &ThemeBuilder) {
+        if !self.accent {
+            t.accent = current.accent;
+        }
+        if !self.bg {
+            t.bg_color = current.bg_color;
+        }
+        if !self.neutral {
+            t.neutral_tint = current.neutral_tint;
+        }
+        if !self.text {
+            t.text_tint = current.text_tint;
+        }
+        if !self.palette {
+            t.palette = current.palette.clone();
+        }
+    }
+}
+
+impl Default for WriteFields {
+    fn default() -> Self {
+        Self { accent: true, bg: true, neutral: true, text: true, palette: true }
+    }
+}
+
+/// A soft bias of the accent towards a band of hues
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HuePreference {
+    #[default]
+    None,
+    /// Reds, oranges and yellows
+    Warm,
+    /// Greens, cyans and blues
+    Cool,
+}
+
+impl HuePreference {
+    /// Whether the hue falls in the preferred band. Every hue matches when
+    /// there is no preference.
+    pub fn matches(self, hue: LabHue) -> bool {
+        let hue = hue.into_positive_degrees();
+        match self {
+            HuePreference::None => true,
+            HuePreference::Warm => !(100. ..330.).contains(&hue),
+            HuePreference::Cool => (140. ..300.).contains(&hue),
+        }
+    }
+}
+
+/// How the semantic palette hues are placed relative to the accent hue
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PaletteHarmony {
+    /// Keep each palette hue, only syncing chroma and lightness to the accent
+    #[default]
+    SyncToAccent,
+    /// Squeeze all hues into a narrow band around the accent
+    Analogous,
+    /// Gather hues around the accent and its complement
+    Complementary,
+    /// Gather hues around the accent and the two hues 120° away from it
+    Triadic,
+}
+
+impl PaletteHarmony {
+    /// Moves `hue` towards the nearest anchor of the harmony, keeping its
+    /// relative position within the anchor's sector so that distinct palette
+    /// colors stay distinct.
+    pub fn place_hue(self, accent: LabHue, hue: LabHue) -> LabHue {
+        let (anchors, spread): (&[f32], f32) = match self {
+            PaletteHarmony::SyncToAccent => return hue,
+            PaletteHarmony::Analogous => (&[0.], 30.),
+            PaletteHarmony::Complementary => (&[0., 180.], 30.),
+            PaletteHarmony::Triadic => (&[0., 120., 240.], 20.),
+        };
+        let half_sector = 180. / anchors.len() as f32;
+        let offset = (hue - accent).into_positive_degrees();
+        let (anchor, d) = anchors
+            .iter()
+            .map(|a| (*a, LabHue::from_degrees(offset - a).into_degrees()))
+            .min_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+            .unwrap_or((0., 0.));
+        accent + LabHue::from_degrees(anchor + d / half_sector * spread)
+    }
+}
+
+/// Where the text tint comes from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextTintStrategy {
+    /// The most common centroid left after picking the other colors
+    #[default]
+    FirstCandidate,
+    /// The hue of the neutral, at a lightness readable on the background
+    NeutralDerived,
+    /// The hue of the accent, at a lightness readable on the background
+    AccentDerived,
+}
+
+/// A text tint with the hue of `source`, at most as colorful as the neutral,
+/// and as far from the lightness of `bg` as needed to reach the minimum
+/// contrast.
+fn derived_text_tint(source: Lch, bg: Lch, bg_config: &MyConfig) -> Srgb {
+    let chroma = source.chroma.min(bg_config.neutral_max_chroma);
+    let tint = Lch::new(100. - bg.l, chroma, source.hue);
+    let tint = adjust_lightness_for_contrast(
+        tint,
+        bg,
+        bg_config.contrast_model,
+        bg_config.min_contrast(),
+        bg_config.contrast_search_steps,
+    );
+    Srgb::from_color(tint.clamp())
+}
+
+impl MyConfig {
+    pub fn min_contrast(&self) -> f32 {
+        self.min_contrast.unwrap_or_else(|| self.contrast_model.default_min_contrast())
+    }
+
+    pub fn min_usable_contrast(&self) -> f32 {
+        self.min_usable_contrast
+            .unwrap_or_else(|| self.contrast_model.default_min_usable_contrast())
+    }
+
+    /// Replaces the names in the avoid lists with their colors from
+    /// `color_aliases`, logging and dropping the names that aren't defined.
+    pub fn resolve_color_aliases(&mut self) {
+        let lists = [
+            ("avoid_accents_light", &mut self.avoid_accents_light),
+            ("avoid_accents_dark", &mut self.avoid_accents_dark),
+            ("avoid_light", &mut self.avoid_light),
+            ("avoid_dark", &mut self.avoid_dark),
+            ("avoid_accents_common", &mut self.avoid_accents_common),
+            ("avoid_common", &mut self.avoid_common),
+        ];
+        for (key, list) in lists {
+            for name in avoid::resolve_aliases(list, &self.color_aliases) {
+                tracing::error!(
+                    "Unknown color alias {:?} in {}, it is not in color_aliases",
+                    name,
+                    key
+                );
+            }
+        }
+    }
+
+    /// Adds the colors of `avoid_accents_common` and `avoid_common` that are
+    /// missing from the dark and light lists.
+    pub fn merge_common_avoid(&mut self) {
+        fn merge(list: &mut Vec<AvoidColor>, common: &[AvoidColor]) {
+            for c in common {
+                if !list.contains(c) {
+                    list.push(c.clone());
+                }
+            }
+        }
+        merge(&mut self.avoid_accents_dark, &self.avoid_accents_common);
+        merge(&mut self.avoid_accents_light, &self.avoid_accents_common);
+        merge(&mut self.avoid_dark, &self.avoid_common);
+        merge(&mut self.avoid_light, &self.avoid_common);
+    }
+
+    /// Sets the knobs `intensity` stands for, from subtle at 0 to bold at 1,
+    /// leaving the ones that were changed from their defaults as they are.
+    /// The default intensity of 0.5 keeps the defaults.
+    pub fn apply_intensity(&mut self) {
+        let default = MyConfig::default();
+        let i = self.intensity.clamp(0., 1.);
+        if self.bg_chroma_delta == default.bg_chroma_delta {
+            self.bg_chroma_delta = lerp(INTENSITY_BG_CHROMA_DELTA, i);
+        }
+        if self.neutral_max_chroma == default.neutral_max_chroma {
+            self.neutral_max_chroma = lerp(INTENSITY_NEUTRAL_MAX_CHROMA, i);
+        }
+        if self.accent_min_final_chroma == default.accent_min_final_chroma {
+            // only the bold half raises the floor of the accent chroma
+            self.accent_min_final_chroma = INTENSITY_ACCENT_MIN_CHROMA * ((i - 0.5) * 2.).max(0.);
+        }
+    }
+
+    /// `max_candidates`, raised to at least [`MIN_CANDIDATES`]
+    pub fn max_candidates(&self) -> usize {
+        if self.max_candidates < MIN_CANDIDATES {
+            tracing::warn!("max_candidates {} is below {}", self.max_candidates, MIN_CANDIDATES);
+        }
+        self.max_candidates.max(MIN_CANDIDATES)
+    }
+
+    /// `kmeans_max_iter`, clamped to [`KMEANS_MAX_ITER`]
+    pub fn kmeans_max_iter(&self) -> usize {
+        if !KMEANS_MAX_ITER.contains(&self.kmeans_max_iter) {
+            tracing::warn!("kmeans_max_iter {} is out of range", self.kmeans_max_iter);
+        }
+        self.kmeans_max_iter.clamp(*KMEANS_MAX_ITER.start(), *KMEANS_MAX_ITER.end())
+    }
+
+    /// `kmeans_convergence`, clamped to [`KMEANS_CONVERGENCE`]
+    pub fn kmeans_convergence(&self) -> f32 {
+        if !KMEANS_CONVERGENCE.contains(&self.kmeans_convergence) {
+            tracing::warn!("kmeans_convergence {} is out of range", self.kmeans_convergence);
+        }
+        self.kmeans_convergence.clamp(*KMEANS_CONVERGENCE.start(), *KMEANS_CONVERGENCE.end())
+    }
+
+    /// The names of all the config keys
+    pub fn keys() -> Vec<String> {
+        match serde_json::to_value(MyConfig::default()) {
+            Ok(serde_json::Value::Object(map)) => map.into_iter().map(|(k, _)| k).collect(),
+            _ => Vec::new(),
         }
     }
 }
 
-fn left_skewed_shuffle<T>(mut v: Vec<T>, max_len_swap: Option<usize>) -> Vec<T> {
+/// Which end of the candidates `randomize` shuffles
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShuffleSkew {
+    /// Move the most common colors around
+    #[default]
+    Left,
+    /// Keep the most common colors in place and only shuffle the tail
+    Right,
+    /// Shuffle all the candidates evenly
+    Uniform,
+}
+
+/// Shuffles `v` according to `skew`.
+///
+/// With `Left`, a partial Fisher-Yates shuffle: each of the first
+/// `max_len_swap` positions (all of them if `None`) is swapped with a random
+/// later position, so the elements at the front are the ones most likely to
+/// move. With `Right`, only the last `max_len_swap` elements are shuffled among
+/// themselves. With `Uniform`, the whole vec is shuffled.
+fn skewed_shuffle<T>(mut v: Vec<T>, max_len_swap: Option<usize>, skew: ShuffleSkew) -> Vec<T> {
     let mut rng = rand::thread_rng();
-    for i in 0..max_len_swap.unwrap_or(v.len()) {
-        let j = rng.gen_range(i..v.len());
-        v.swap(i, j);
+    // swapping past the end would sample from an empty range
+    let max_len_swap = max_len_swap.map_or(v.len(), |max| max.min(v.len()));
+    match skew {
+        ShuffleSkew::Left => {
+            for i in 0..max_len_swap {
+                let j = rng.gen_range(i..v.len());
+                v.swap(i, j);
+            }
+        },
+        ShuffleSkew::Right => {
+            let start = v.len() - max_len_swap;
+            v[start..].shuffle(&mut rng);
+        },
+        ShuffleSkew::Uniform => v.shuffle(&mut rng),
     }
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageBuffer, ImageFormat, Rgb};
+
+    use super::*;
+
+    const MUD: Srgb = Srgb::new(0.54, 0.38, 0.28);
+
+    #[test]
+    fn similar_centroids_are_merged() {
+        let centroid = |l: f32, percentage: f32, index: u8| CentroidData {
+            centroid: Lab::new(l, 20., 20.),
+            percentage,
+            index,
+        };
+        // clusters 0 and 2 are one region split in two
+        #[rustfmt::skip]
+        let indices = [
+            0, 0, 2, 2,
+            1, 1, 1, 1,
+        ];
+        let (merged, indices) = merge_similar_centroids(
+            vec![centroid(50., 0.25, 0), centroid(80., 0.5, 1), centroid(52., 0.25, 2)],
+            &indices,
+            5.,
+        );
+        assert_eq!(merged.len(), 2);
+        assert_eq!((merged[0].centroid.l, merged[0].index), (80., 0));
+        assert_eq!((merged[1].centroid.l, merged[1].index), (50., 1));
+        assert!((merged[1].percentage - 0.5).abs() < 1e-6);
+        assert_eq!(indices, vec![1, 1, 1, 1, 0, 0, 0, 0]);
+        // the merged cluster counts as one region for region_weighting
+        assert_eq!(region::largest_regions(&indices, 4, merged.len()), vec![4, 4]);
+    }
+
+    #[test]
+    fn avoid_matches_inside_ellipse() {
+        let mut c = Lch::from_color(MUD);
+        c.chroma += 25.;
+        assert!(is_near_avoid(c, &[MUD], &AvoidTolerance::default()));
+    }
+
+    #[test]
+    fn avoid_rejects_outside_ellipse() {
+        let mut c = Lch::from_color(MUD);
+        c.chroma += 26.;
+        assert!(!is_near_avoid(c, &[MUD], &AvoidTolerance::default()));
+    }
+
+    #[test]
+    fn avoid_rejects_large_hue_difference() {
+        let mut c = Lch::from_color(MUD);
+        c.hue += 21.;
+        // 21² = 441 is inside the ellipse, but the hue limit is exceeded
+        assert!(!is_near_avoid(c, &[MUD], &AvoidTolerance::default()));
+    }
+
+    #[test]
+    fn avoid_respects_custom_tolerance() {
+        let mut c = Lch::from_color(MUD);
+        c.chroma += 25.;
+        let tolerance = AvoidTolerance { max_combined_sq: 600., ..Default::default() };
+        assert!(!is_near_avoid(c, &[MUD], &tolerance));
+    }
+
+    #[test]
+    fn percentage_weight_favors_prominent_accents() {
+        let calm = Lab::new(60., 30., 20.);
+        let vivid = Lab::new(60., 70., 40.);
+        let candidates = [calm, vivid];
+        let shares = [(calm, 0.6), (vivid, 0.02)];
+        let bg = Lch::new(15., 0., 0.);
+        let mut config = MyConfig::default();
+        let picked = pick_accent(&candidates, &shares, 0, bg, &[], None, &config);
+        assert_eq!(picked.unwrap().0, vivid);
+
+        config.accent_percentage_weight = 1.;
+        let picked = pick_accent(&candidates, &shares, 0, bg, &[], None, &config);
+        assert_eq!(picked.unwrap().0, calm);
+    }
+
+    #[test]
+    fn semantic_red_keeps_its_hue() {
+        let mut t = ThemeBuilder::dark();
+        let red = Lch::from_color(t.palette.as_ref().accent_red);
+        let accent = Srgb::new(0.2, 0.4, 0.9);
+        let config = MyConfig {
+            palette_harmony: PaletteHarmony::Analogous,
+            preserve_semantic_red: true,
+            ..Default::default()
+        };
+        sync_palette(&mut t, accent, &config);
+        let synced = Lch::from_color(t.palette.as_ref().accent_red);
+        assert!((synced.hue - red.hue).into_inner().abs() < 10.);
+        assert!((synced.l - Lch::from_color(accent).l).abs() < 5.);
+    }
+
+    #[test]
+    fn equalized_palette_is_in_gamut() {
+        let mut t = ThemeBuilder::dark();
+        // a vivid blue, whose chroma most hues can't reach at its lightness
+        let accent = Srgb::new(0.1, 0.2, 1.);
+        let config = MyConfig { equalize_palette_chroma: true, ..Default::default() };
+        sync_palette(&mut t, accent, &config);
+        let p = t.palette.as_ref();
+        let chromas = [p.accent_green, p.accent_orange, p.accent_yellow, p.ext_pink]
+            .map(|c| Lch::from_color(c).chroma);
+        let (min, max) =
+            chromas.iter().fold((f32::MAX, f32::MIN), |(lo, hi), c| (lo.min(*c), hi.max(*c)));
+        assert!(max - min < 2.);
+    }
+
+    #[test]
+    fn gamut_map_keeps_the_hue() {
+        let c = Lch::new(50., 150., 140.);
+        let fitted = fit_gamut(c, true);
+        assert!(Srgb::from_color_unclamped(fitted).is_within_bounds());
+        assert!(fitted.chroma < c.chroma);
+        let srgb = Srgb::from_color(fitted);
+        assert!((Lch::from_color(srgb).hue - c.hue).into_inner().abs() < 1.);
+    }
+
+    #[test]
+    fn hue_shifted_accent_is_finished() {
+        let bg = Lch::from_color(Theme::light_default().background.base);
+        let fallback = Lch::from_color(Theme::light_default().accent.base);
+        // the hue shift of an accent too close to the neutral leaves the gamut
+        let mut shifted = Lch::new(45., 130., 250.);
+        shifted.hue += 30.;
+
+        let config = MyConfig { gamut_map: true, ..Default::default() };
+        let accent = finish_accent(shifted, bg, fallback, false, &config);
+        assert!(Srgb::from_color_unclamped(accent).is_within_bounds());
+        assert!(config.contrast_model.contrast(accent, bg) >= config.min_usable_contrast());
+
+        let config = MyConfig { monochrome: true, ..config };
+        assert_eq!(finish_accent(shifted, bg, fallback, false, &config).chroma, 0.);
+    }
+
+    #[test]
+    fn neutral_chroma_is_capped_per_mode() {
+        let config = MyConfig { neutral_max_chroma_light: Some(4.), ..Default::default() };
+        let neutral = Srgb::from_color(Lch::new(60., 25., 200.));
+        let light = cap_neutral_chroma(neutral, false, &config);
+        assert!(light.chroma <= 4.);
+        assert!((light.l - 60.).abs() < 0.1);
+        let dark = cap_neutral_chroma(neutral, true, &config);
+        assert!((dark.chroma - 25.).abs() < 0.1);
+    }
+
+    #[test]
+    fn derived_text_tint_is_readable() {
+        let config = MyConfig::default();
+        let bg = Lch::new(20., 5., 250.);
+        let neutral = Lch::new(40., 15., 30.);
+        let text = Lch::from_color(derived_text_tint(neutral, bg, &config));
+        assert!(config.contrast_model.contrast(text, bg) >= config.min_contrast() - 0.1);
+        assert!((text.hue - neutral.hue).into_inner().abs() < 5.);
+    }
+
+    #[test]
+    fn intensity_maps_to_the_unchanged_knobs() {
+        let mut config = MyConfig::default();
+        config.apply_intensity();
+        assert_eq!(config, MyConfig::default());
+
+        let mut config = MyConfig { intensity: 1., neutral_max_chroma: 12., ..Default::default() };
+        config.apply_intensity();
+        assert_eq!(config.bg_chroma_delta, 25.);
+        assert_eq!(config.accent_min_final_chroma, INTENSITY_ACCENT_MIN_CHROMA);
+        assert_eq!(config.neutral_max_chroma, 12.);
+    }
+
+    #[test]
+    fn reconnects_back_off_when_rapid() {
+        assert_eq!(reconnect_delay(0, Duration::ZERO, 1), RECONNECT_COOLDOWN);
+        assert_eq!(reconnect_delay(3, Duration::MAX, 1), Duration::from_secs(8));
+        let rapid = MAX_RAPID_RECONNECTS + 1;
+        assert_eq!(reconnect_delay(0, Duration::ZERO, rapid), RAPID_RECONNECT_BACKOFF);
+    }
+
+    #[test]
+    fn distinctiveness_weight_favors_accents_far_from_the_bg() {
+        // equally vivid, one close to the lightness of the background
+        let near = Lab::new(25., 40., 30.);
+        let far = Lab::new(75., 40., 30.);
+        let bg = Lch::new(20., 20., 40.);
+        let config = MyConfig {
+            accent_chroma_weight: 0.,
+            accent_distinctiveness_weight: 1.,
+            min_contrast: Some(1.),
+            ..Default::default()
+        };
+        let picked = pick_accent(&[near, far], &[], 0, bg, &[], None, &config);
+        assert_eq!(picked.unwrap().0, far);
+    }
+
+    #[test]
+    fn no_accent_from_filtered_out_candidates() {
+        // a single color image leaves one centroid, which the background takes
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(8, 8, Rgb([140, 80, 40])));
+        let (pixels, _) = sample_pixels(img, 8, false).unwrap();
+        let config = MyConfig::default();
+        let mut res = vec![pixels[0]];
+        let default_bg = Lch::from_color(Theme::dark_default().background.base);
+        let bg = pick_bg(&mut res, default_bg, &[], &config).unwrap();
+        assert!(res.is_empty());
+        assert!(pick_accent(&res, &[], 0, bg, &[], None, &config).is_none());
+    }
+
+    #[test]
+    fn bad_user_key_keeps_the_others() {
+        let dir = std::env::temp_dir().join(format!("{}-config-{}", ID, std::process::id()));
+        let user_config = Config::with_custom_path(ID, MyConfig::VERSION, dir.clone()).unwrap();
+        user_config.set("randomize", false).unwrap();
+        user_config.set("contrast_model", ContrastModel::Apca).unwrap();
+        let key_dir = dir.join("cosmic").join(ID).join(format!("v{}", MyConfig::VERSION));
+        std::fs::write(key_dir.join("sample_width"), "\"wide\"").unwrap();
+        let config = load_config_layers(Some(&user_config));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!config.randomize);
+        assert_eq!(config.contrast_model, ContrastModel::Apca);
+        assert_eq!(config.sample_width, MyConfig::default().sample_width);
+    }
+
+    #[test]
+    fn default_early_accept_is_a_third() {
+        let fraction = MyConfig::default().accent_early_accept_fraction;
+        for len in 0..=64 {
+            assert_eq!(early_accept_len(len, fraction), len / 3, "{len}");
+        }
+        assert_eq!(early_accept_len(9, 2.), 9);
+    }
+
+    #[test]
+    fn dominant_bg_keeps_the_other_accent_candidates() {
+        let lab = |l: f32, chroma: f32, hue: f32| Lab::from_color(Lch::new(l, chroma, hue));
+        let sky = lab(60., 30., 250.);
+        let others = [lab(50., 60., 30.), lab(70., 50., 120.), lab(40., 40., 160.)];
+        let shares = [(sky, 0.6), (others[0], 0.2), (others[1], 0.15), (others[2], 0.05)];
+        let config = MyConfig { skip_dominant_for_accent: true, ..Default::default() };
+        let dominant = dominant_color(&shares, &config);
+        assert_eq!(dominant, Some(sky));
+
+        let mut res = vec![sky, others[0], others[1], others[2]];
+        let default_bg = Lch::from_color(Theme::dark_default().background.base);
+        let bg = pick_bg(&mut res, default_bg, &[], &config).unwrap();
+        assert!((bg.hue - Lch::from_color(sky).hue).into_inner().abs() < 1.);
+        assert_eq!(accent_pool(&res, dominant, &config), others);
+
+        // still among the candidates when the background is preserved
+        let res = vec![others[0], sky, others[1], others[2]];
+        assert_eq!(accent_pool(&res, dominant, &config), others);
+    }
+
+    #[test]
+    fn lightness_bias_stops_at_the_contrast_limit() {
+        let bg: Lch = Srgb::new(0.1, 0.1, 0.1).into_color();
+        let accent = Lch::new(70., 40., 250.);
+        let model = ContrastModel::default();
+        let min = model.default_min_contrast();
+        let brighter = bias_lightness(accent, 10., bg, model, min);
+        assert!(brighter.l > accent.l);
+        let darker = bias_lightness(accent, -60., bg, model, min);
+        assert!(darker.l < accent.l);
+        assert!(model.contrast(darker, bg) >= min);
+    }
+
+    #[test]
+    fn lazy_update_keeps_readable_themes() {
+        let bg: Lch = Srgb::new(0.1, 0.1, 0.12).into_color();
+        let light = Some(Srgb::new(0.9, 0.9, 0.9));
+        let dark = Some(Srgb::new(0.15, 0.15, 0.15));
+        let model = ContrastModel::default();
+        let min = model.default_min_contrast();
+        assert!(still_readable(light, light, bg, model, min));
+        assert!(!still_readable(light, dark, bg, model, min));
+        assert!(!still_readable(None, light, bg, model, min));
+    }
+
+    #[test]
+    fn shuffle_only_randomizes_front() {
+        for _ in 0..100 {
+            let v = skewed_shuffle((0..10).collect::<Vec<usize>>(), Some(3), ShuffleSkew::Left);
+            // past the front, a position either keeps its element or holds one
+            // swapped out of the front
+            assert!(v.iter().enumerate().skip(3).all(|(i, c)| *c == i || *c < 3));
+        }
+    }
+
+    #[test]
+    fn shuffle_is_permutation() {
+        for max_len_swap in [None, Some(0), Some(3), Some(10), Some(20)] {
+            for skew in [ShuffleSkew::Left, ShuffleSkew::Right, ShuffleSkew::Uniform] {
+                let mut v = skewed_shuffle((0..10).collect::<Vec<usize>>(), max_len_swap, skew);
+                v.sort_unstable();
+                assert_eq!(v, (0..10).collect::<Vec<usize>>());
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_handles_short_vecs() {
+        for skew in [ShuffleSkew::Left, ShuffleSkew::Right, ShuffleSkew::Uniform] {
+            assert!(skewed_shuffle(Vec::<usize>::new(), Some(3), skew).is_empty());
+            assert_eq!(skewed_shuffle(vec![1], Some(3), skew), vec![1]);
+        }
+    }
+
+    #[test]
+    fn right_skew_keeps_the_front() {
+        for _ in 0..100 {
+            let v = skewed_shuffle((0..10).collect::<Vec<usize>>(), Some(3), ShuffleSkew::Right);
+            assert_eq!(v[..7], [0, 1, 2, 3, 4, 5, 6]);
+        }
+    }
+
+    #[test]
+    fn shuffle_is_left_skewed() {
+        let runs = 10_000;
+        let mut first_kept = 0;
+        let mut last_kept = 0;
+        let mut at_front = [0; 10];
+        for _ in 0..runs {
+            let v = skewed_shuffle((0..10).collect::<Vec<usize>>(), Some(3), ShuffleSkew::Left);
+            first_kept += usize::from(v[0] == 0);
+            last_kept += usize::from(v[9] == 9);
+            at_front[v[0]] += 1;
+        }
+        // the first element stays with probability 1/10, the last with 7/10
+        assert!(last_kept > first_kept * 3);
+        // every element is equally likely to end up at the front
+        for count in at_front {
+            let freq = count as f32 / runs as f32;
+            assert!((0.07..0.13).contains(&freq), "{freq}");
+        }
+    }
+
+    #[test]
+    fn accent_falls_back_without_usable_contrast() {
+        // no lightness reaches a contrast of 5 on a mid grey
+        let bg = Lch::from_color(Srgb::new(0.47, 0.47, 0.47));
+        let candidate = Lch::from_color(Srgb::new(0.9, 0.2, 0.1));
+        let model = ContrastModel::Wcag21;
+        let adjusted = adjust_lightness_for_contrast(candidate, bg, model, 5., 40);
+        let fallback = Lch::from_color(Srgb::new(0.39, 0.81, 1.));
+        assert_eq!(usable_accent_or(adjusted, bg, model, 5., fallback), fallback);
+    }
+
+    #[test]
+    fn accent_kept_with_usable_contrast() {
+        let bg = Lch::from_color(Srgb::new(0.1, 0.1, 0.1));
+        let candidate = Lch::from_color(Srgb::new(0.9, 0.2, 0.1));
+        let model = ContrastModel::Wcag21;
+        let adjusted = adjust_lightness_for_contrast(candidate, bg, model, 4.5, 40);
+        let fallback = Lch::from_color(Srgb::new(0.39, 0.81, 1.));
+        assert_eq!(usable_accent_or(adjusted, bg, model, 3., fallback), adjusted);
+    }
+
+    #[test]
+    fn disabled_fields_are_untouched() {
+        let current = ThemeBuilder::dark().accent(Srgb::new(0.1, 0.2, 0.3));
+        let mut t = current
+            .clone()
+            .accent(Srgb::new(0.9, 0.1, 0.1))
+            .bg_color(Srgba::new(0.2, 0.2, 0.2, 1.))
+            .text_tint(Srgb::new(0.5, 0.5, 0.5));
+        let fields = WriteFields { accent: false, text: false, ..WriteFields::default() };
+        fields.keep_disabled(&mut t, &current);
+        assert_eq!(t.accent, current.accent);
+        assert_eq!(t.text_tint, current.text_tint);
+        assert_eq!(t.bg_color, Some(Srgba::new(0.2, 0.2, 0.2, 1.)));
+    }
+
+    #[test]
+    fn all_fields_are_written_by_default() {
+        let current = ThemeBuilder::dark();
+        let mut t = current.clone().accent(Srgb::new(0.9, 0.1, 0.1));
+        WriteFields::default().keep_disabled(&mut t, &current);
+        assert_eq!(t.accent, Some(Srgb::new(0.9, 0.1, 0.1)));
+    }
+
+    #[test]
+    fn sample_16_bit_png() {
+        let img =
+            DynamicImage::ImageRgb16(ImageBuffer::from_pixel(512, 128, Rgb([u16::MAX, 0, 0])));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        assert!(matches!(img, DynamicImage::ImageRgb16(_)));
+
+        let (pixels, width) = sample_pixels(img, 256, false).unwrap();
+        assert_eq!((pixels.len(), width), (256 * 64, 256));
+        let red: Lab = Srgb::new(1., 0., 0.).into_color();
+        assert!(pixels.iter().all(|p| (p.l - red.l).abs() < 1. && (p.a - red.a).abs() < 1.));
+    }
+
+    #[test]
+    fn small_images_are_not_upscaled() {
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(100, 50, Rgb([0, 0, 255])));
+        let (pixels, width) = sample_pixels(img, 256, false).unwrap();
+        assert_eq!((pixels.len(), width), (100 * 50, 100));
+    }
+}
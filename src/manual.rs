@@ -0,0 +1,47 @@
+//! Applying colors given on the command line, without a wallpaper
+
+use cosmic_config::CosmicConfigEntry;
+use cosmic_theme::ThemeBuilder;
+use palette::Srgba;
+
+use crate::{hex, load_config, parse_hex, sync_palette, write_theme};
+
+/// Applies `accent`, and `bg` and `neutral` if given, to the current theme of
+/// each of `modes`, harmonizing the palette around the accent as the daemon
+/// does. The colors are `#rrggbb` hex strings. The parts disabled in
+/// `write_fields` keep their current values.
+pub fn apply(
+    accent: &str,
+    bg: Option<&str>,
+    neutral: Option<&str>,
+    modes: &[bool],
+) -> anyhow::Result<()> {
+    let accent = parse_hex(accent)?;
+    let bg = bg.map(parse_hex).transpose()?;
+    let neutral = neutral.map(parse_hex).transpose()?;
+    let bg_config = load_config();
+
+    for &is_dark in modes {
+        let builder_config =
+            if is_dark { ThemeBuilder::dark_config()? } else { ThemeBuilder::light_config()? };
+        let current = ThemeBuilder::get_entry(&builder_config).unwrap_or_else(|(_, entry)| entry);
+        let mut t = current.clone().accent(accent);
+        if let Some(bg) = bg {
+            t = t.bg_color(Srgba::new(bg.red, bg.green, bg.blue, 1.));
+        }
+        if let Some(neutral) = neutral {
+            t = t.neutral_tint(neutral);
+        }
+        if bg_config.harmonize_palette {
+            sync_palette(&mut t, accent, &bg_config);
+        }
+        bg_config.write_fields.keep_disabled(&mut t, &current);
+        write_theme(t, is_dark, &bg_config)?;
+        println!(
+            "Applied the {} theme with accent {}",
+            if is_dark { "dark" } else { "light" },
+            hex(accent)
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Approximate names of colors for log messages
+
+use palette::{IntoColor, Lab, Srgb};
+
+/// Named reference colors, as sRGB hex
+const NAMED: &[(&str, u32)] = &[
+    ("black", 0x000000),
+    ("charcoal", 0x36454f),
+    ("grey", 0x808080),
+    ("silver", 0xc0c0c0),
+    ("white", 0xffffff),
+    ("red", 0xd62828),
+    ("maroon", 0x800000),
+    ("brick", 0xa0493a),
+    ("pink", 0xf4a6c0),
+    ("magenta", 0xc2188f),
+    ("orange", 0xf28c28),
+    ("brown", 0x7b4a25),
+    ("tan", 0xc8a97e),
+    ("yellow", 0xf2d42c),
+    ("olive", 0x808000),
+    ("lime", 0x9bd247),
+    ("green", 0x2e8b3e),
+    ("forest green", 0x1f4d2b),
+    ("sage", 0x9caf88),
+    ("mint", 0x98e0bd),
+    ("teal", 0x1b8a8a),
+    ("muted teal", 0x5f9e9a),
+    ("cyan", 0x2cc5d6),
+    ("sky blue", 0x7ec4ef),
+    ("blue", 0x2f6fd6),
+    ("navy", 0x1c2a5a),
+    ("slate blue", 0x6a7fa8),
+    ("indigo", 0x4b3a9e),
+    ("lavender", 0xb7a6e0),
+    ("purple", 0x7d3c98),
+    ("plum", 0x8e4585),
+    ("beige", 0xe8dcc2),
+];
+
+/// The name of the reference color nearest to `c`
+pub fn nearest_name(c: Srgb) -> &'static str {
+    let c: Lab = c.into_color();
+    NAMED
+        .iter()
+        .map(|(name, rgb)| {
+            let named: Lab = Srgb::from(*rgb).into_format::<f32>().into_color();
+            let dist = (c.l - named.l).powi(2) + (c.a - named.a).powi(2) + (c.b - named.b).powi(2);
+            (name, dist)
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or("unknown", |(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_reference_colors() {
+        assert_eq!(nearest_name(Srgb::new(0., 0., 0.)), "black");
+        assert_eq!(nearest_name(Srgb::new(0.11, 0.55, 0.54)), "teal");
+        assert_eq!(nearest_name(Srgb::new(0.95, 0.55, 0.17)), "orange");
+    }
+}
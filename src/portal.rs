@@ -0,0 +1,82 @@
+//! Reading wallpapers through the document portal, for confined sessions
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use cosmic_bg_config::state::State;
+use cosmic_bg_config::Source;
+use zbus::Connection;
+
+use crate::remote;
+
+#[zbus::proxy(
+    interface = "org.freedesktop.portal.Documents",
+    default_service = "org.freedesktop.portal.Documents",
+    default_path = "/org/freedesktop/portal/documents"
+)]
+trait Documents {
+    /// Where the document store is mounted, NUL terminated
+    fn get_mount_point(&self) -> zbus::Result<Vec<u8>>;
+    /// The id of the exported document of a file, empty if it isn't exported
+    fn lookup(&self, filename: &[u8]) -> zbus::Result<String>;
+}
+
+/// The state with each wallpaper that can't be read directly replaced by its
+/// copy in the document store, if it has been exported there.
+///
+/// Returns `None` if the portal isn't available or no wallpaper needed it, so
+/// that the wallpapers are read directly.
+pub async fn resolve(conn: &Connection, state: &State) -> Option<State> {
+    // remote wallpapers are downloaded, not read
+    let unreadable = |path: &Path| !remote::is_url(path) && std::fs::File::open(path).is_err();
+    let any_unreadable = state
+        .wallpapers
+        .iter()
+        .any(|(_, source)| matches!(source, Source::Path(path) if unreadable(path)));
+    if !any_unreadable {
+        return None;
+    }
+
+    let proxy = match DocumentsProxy::new(conn).await {
+        Ok(proxy) => proxy,
+        Err(err) => {
+            tracing::debug!("The document portal is unavailable: {}", err);
+            return None;
+        },
+    };
+    let mount_point = match proxy.get_mount_point().await {
+        Ok(mount_point) => nul_terminated_path(&mount_point),
+        Err(err) => {
+            tracing::debug!("Failed to get the document store: {}", err);
+            return None;
+        },
+    };
+
+    let mut resolved = state.clone();
+    let mut any = false;
+    for (output, source) in &mut resolved.wallpapers {
+        let Source::Path(path) = source else {
+            continue;
+        };
+        if !unreadable(path) {
+            continue;
+        }
+        let mut filename = path.as_os_str().as_bytes().to_vec();
+        filename.push(0);
+        let id = proxy.lookup(&filename).await.ok().filter(|id| !id.is_empty());
+        let (Some(name), Some(id)) = (path.file_name(), id) else {
+            tracing::debug!("The wallpaper of {} isn't in the document store", output);
+            continue;
+        };
+        *path = mount_point.join(id).join(name);
+        any = true;
+    }
+    any.then_some(resolved)
+}
+
+/// A path from a portal byte string, without its trailing NUL
+fn nul_terminated_path(bytes: &[u8]) -> PathBuf {
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
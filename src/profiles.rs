@@ -0,0 +1,170 @@
+//! Config overrides for the wallpapers under a directory
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::MyConfig;
+
+/// Config keys overridden for the wallpapers under `directory`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirectoryProfile {
+    /// The directory, with a leading `~` standing for the home directory
+    pub directory: PathBuf,
+    /// Config keys and the values they take, as in the config
+    pub overrides: BTreeMap<String, serde_json::Value>,
+}
+
+/// `config` with the overrides of each profile whose directory contains
+/// `wallpaper` merged over it. Profiles of deeper directories are merged last,
+/// so that they take precedence.
+///
+/// `config` is the layered config as loaded, before the aliases, common avoid
+/// lists and intensity are applied, see [`crate::resolve_config`].
+///
+/// Symlinks are resolved like for the cache keys, so that a stable link to the
+/// current wallpaper gets the profile of the directory of its target.
+pub fn apply(config: MyConfig, wallpaper: &Path) -> MyConfig {
+    let wallpaper = canonicalize(wallpaper);
+    let mut matching: Vec<(usize, &DirectoryProfile)> = config
+        .directory_profiles
+        .iter()
+        .filter_map(|profile| {
+            let directory = canonicalize(&expand_home(&profile.directory));
+            wallpaper.starts_with(&directory).then(|| (directory.components().count(), profile))
+        })
+        .collect();
+    if matching.is_empty() {
+        return config;
+    }
+    matching.sort_by_key(|(depth, _)| *depth);
+
+    let Ok(serde_json::Value::Object(mut merged)) = serde_json::to_value(&config) else {
+        tracing::error!("Failed to serialize the config to apply the directory profiles");
+        return config;
+    };
+    for (_, profile) in matching {
+        tracing::debug!("Applying the profile of {}", profile.directory.display());
+        for (key, value) in &profile.overrides {
+            if merged.contains_key(key) {
+                merged.insert(key.clone(), value.clone());
+            } else {
+                tracing::error!(
+                    "Unknown key {} in the profile of {}",
+                    key,
+                    profile.directory.display()
+                );
+            }
+        }
+    }
+    match serde_json::from_value(serde_json::Value::Object(merged)) {
+        Ok(merged) => merged,
+        Err(err) => {
+            tracing::error!("Failed to apply the directory profiles: {}", err);
+            config
+        },
+    }
+}
+
+/// `path` with its symlinks resolved, or as it is if it can't be resolved
+fn canonicalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `path` with a leading `~` replaced with the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use palette::Srgb;
+
+    use super::*;
+    use crate::avoid::AvoidColor;
+    use crate::resolve_config;
+
+    #[test]
+    fn deeper_profiles_take_precedence() {
+        let profile = |directory: &str, delta: f32| DirectoryProfile {
+            directory: directory.into(),
+            overrides: [("bg_chroma_delta".to_string(), delta.into())].into(),
+        };
+        let config = MyConfig {
+            directory_profiles: vec![
+                profile("/pictures/dark/night", 5.),
+                profile("/pictures/dark", 10.),
+            ],
+            ..Default::default()
+        };
+
+        let outside = apply(config.clone(), Path::new("/pictures/vivid/a.png"));
+        assert_eq!(outside.bg_chroma_delta, MyConfig::default().bg_chroma_delta);
+        let dark = apply(config.clone(), Path::new("/pictures/dark/a.png"));
+        assert_eq!(dark.bg_chroma_delta, 10.);
+        let night = apply(config, Path::new("/pictures/dark/night/a.png"));
+        assert_eq!(night.bg_chroma_delta, 5.);
+    }
+
+    #[test]
+    fn symlinked_wallpapers_get_the_profile_of_their_target() {
+        let dir =
+            std::env::temp_dir().join(format!("{}-profiles-{}", crate::ID, std::process::id()));
+        let dark = dir.join("dark");
+        std::fs::create_dir_all(&dark).unwrap();
+        let (target, link) = (dark.join("x.png"), dir.join("current.png"));
+        std::fs::write(&target, b"").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let config = MyConfig {
+            directory_profiles: vec![DirectoryProfile {
+                directory: dark,
+                overrides: [("bg_chroma_delta".to_string(), 10.into())].into(),
+            }],
+            ..Default::default()
+        };
+        let linked = apply(config, &link);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(linked.bg_chroma_delta, 10.);
+    }
+
+    #[test]
+    fn profile_intensity_scales_the_defaults_once() {
+        let config = MyConfig {
+            intensity: 0.8,
+            directory_profiles: vec![DirectoryProfile {
+                directory: "/pictures/calm".into(),
+                overrides: [("intensity".to_string(), 0.2.into())].into(),
+            }],
+            ..Default::default()
+        };
+        let global = resolve_config(config.clone(), Some(Path::new("/pictures/a.png")));
+        let calm = resolve_config(config, Some(Path::new("/pictures/calm/a.png")));
+        assert!(calm.bg_chroma_delta < MyConfig::default().bg_chroma_delta);
+        assert!(global.bg_chroma_delta > MyConfig::default().bg_chroma_delta);
+        let mut expected = MyConfig { intensity: 0.2, ..Default::default() };
+        expected.apply_intensity();
+        assert_eq!(calm.bg_chroma_delta, expected.bg_chroma_delta);
+    }
+
+    #[test]
+    fn profile_avoid_lists_are_resolved_and_merged_once() {
+        let common = AvoidColor::Color(Srgb::new(0.1, 0.2, 0.3));
+        let config = MyConfig {
+            color_aliases: [("mud".to_string(), [0.54, 0.38, 0.28])].into(),
+            avoid_accents_common: vec![common.clone()],
+            directory_profiles: vec![DirectoryProfile {
+                directory: "/pictures/earthy".into(),
+                overrides: [("avoid_accents_dark".to_string(), serde_json::json!(["mud"]))].into(),
+            }],
+            ..Default::default()
+        };
+        let earthy = resolve_config(config, Some(Path::new("/pictures/earthy/a.png")));
+        let mud = AvoidColor::Color(Srgb::new(0.54, 0.38, 0.28));
+        assert_eq!(earthy.avoid_accents_dark, vec![mud, common]);
+    }
+}
@@ -0,0 +1,59 @@
+//! Connected-region analysis of the clustered sample
+
+/// The size in pixels of the largest 4-connected region of each cluster.
+///
+/// `indices` holds the cluster of each pixel of the sample, row by row, with
+/// `width` pixels per row.
+pub fn largest_regions(indices: &[u8], width: usize, clusters: usize) -> Vec<usize> {
+    let mut largest = vec![0; clusters];
+    if width == 0 {
+        return largest;
+    }
+    let mut seen = vec![false; indices.len()];
+    let mut stack = Vec::new();
+    for start in 0..indices.len() {
+        if seen[start] {
+            continue;
+        }
+        let cluster = indices[start];
+        seen[start] = true;
+        stack.push(start);
+        let mut size = 0;
+        while let Some(i) = stack.pop() {
+            size += 1;
+            let x = i % width;
+            let neighbors = [
+                i.checked_sub(width),
+                (i + width < indices.len()).then_some(i + width),
+                (x > 0).then(|| i - 1),
+                (x + 1 < width && i + 1 < indices.len()).then_some(i + 1),
+            ];
+            for n in neighbors.into_iter().flatten() {
+                if !seen[n] && indices[n] == cluster {
+                    seen[n] = true;
+                    stack.push(n);
+                }
+            }
+        }
+        if let Some(l) = largest.get_mut(cluster as usize) {
+            *l = (*l).max(size);
+        }
+    }
+    largest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concentrated_cluster_beats_scattered() {
+        // cluster 0 is a solid block, cluster 1 the same area as a checkerboard
+        #[rustfmt::skip]
+        let indices = [
+            0, 0, 1, 2,
+            0, 0, 2, 1,
+        ];
+        assert_eq!(largest_regions(&indices, 4, 3), vec![4, 1, 1]);
+    }
+}
@@ -0,0 +1,74 @@
+//! Fetching wallpapers given as http(s) URLs
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cache;
+
+/// Largest wallpaper downloaded
+#[cfg(feature = "remote")]
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// How long a download may take
+#[cfg(feature = "remote")]
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a downloaded wallpaper is reused before it is fetched again
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Whether the wallpaper is an http(s) URL rather than a file
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.starts_with("http://") || p.starts_with("https://"))
+}
+
+/// The local copy of the wallpaper at `url`. It is downloaded unless a copy
+/// fetched less than an hour ago is cached, so that both modes and repeated
+/// extractions share one download.
+pub fn fetch(url: &Path, allow_remote: bool) -> anyhow::Result<PathBuf> {
+    if !allow_remote {
+        anyhow::bail!("Remote wallpapers are disabled, set allow_remote to enable them");
+    }
+    let Some(url) = url.to_str() else {
+        anyhow::bail!("Invalid wallpaper URL {}", url.display());
+    };
+    let Some(dir) = cache::cache_dir() else {
+        anyhow::bail!("Failed to find the cache directory");
+    };
+    let file = dir.join("remote").join(format!("{:x}", md5::compute(url)));
+
+    let fresh = std::fs::metadata(&file)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < MAX_AGE));
+    if !fresh {
+        tracing::info!("Downloading the wallpaper {}", url);
+        download(url, &file)?;
+    }
+    Ok(file)
+}
+
+#[cfg(feature = "remote")]
+fn download(url: &str, file: &Path) -> anyhow::Result<()> {
+    use std::io::Read;
+
+    let response = ureq::get(url).timeout(TIMEOUT).call()?;
+    let len = response.header("Content-Length").and_then(|len| len.parse::<u64>().ok());
+    let mut bytes = Vec::new();
+    if len.unwrap_or(0) <= MAX_SIZE {
+        response.into_reader().take(MAX_SIZE + 1).read_to_end(&mut bytes)?;
+    }
+    if len.unwrap_or(0) > MAX_SIZE || bytes.len() as u64 > MAX_SIZE {
+        anyhow::bail!("The wallpaper at {url} is larger than {} MiB", MAX_SIZE / 1024 / 1024);
+    }
+
+    // an interrupted download must not be mistaken for the wallpaper
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let partial = file.with_extension("part");
+    std::fs::write(&partial, bytes)?;
+    std::fs::rename(partial, file)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "remote"))]
+fn download(_url: &str, _file: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("Remote wallpapers require the remote feature")
+}
@@ -0,0 +1,159 @@
+//! Cycling the accent through the candidates of the last extraction
+
+use std::time::Duration;
+
+use cosmic_config::{ConfigGet, ConfigSet, CosmicConfigEntry};
+use cosmic_theme::{Theme, ThemeBuilder};
+use palette::{FromColor, IntoColor, Lab, Lch, Srgb};
+use zbus::Connection;
+
+use crate::{
+    adjust_lightness_for_contrast, cache, emit_theme_generated, finish_accent, fit_gamut,
+    is_near_avoid, load_config, spawn_post_apply_cmd, sync_palette, write_theme, BgResult,
+    MyConfig, ID,
+};
+
+/// Accents cycled through, including the one picked by the extraction
+const CANDIDATES: usize = 3;
+/// Hue difference in degrees for a candidate to count as a different accent
+const MIN_HUE_DIFFERENCE: f32 = 30.;
+
+/// The accent followed by up to two other colors of `pool` with distinct hues,
+/// adjusted for contrast on `bg` and finished like the accent.
+pub fn candidates(
+    accent: Srgb,
+    pool: &[Lab],
+    bg: Lch,
+    fallback: Lch,
+    is_dark: bool,
+    avoid: &[Srgb],
+    bg_config: &MyConfig,
+) -> Vec<Srgb> {
+    let mut candidates = vec![Lch::from_color(accent)];
+    for c in pool {
+        if candidates.len() >= CANDIDATES {
+            break;
+        }
+        let c = Lch::from_color(*c);
+        if c.chroma < 10. {
+            continue;
+        }
+        let adjusted = adjust_lightness_for_contrast(
+            c,
+            bg,
+            bg_config.contrast_model,
+            bg_config.min_contrast(),
+            bg_config.contrast_search_steps,
+        );
+        let adjusted = finish_accent(adjusted, bg, fallback, is_dark, bg_config);
+        let distinct = candidates
+            .iter()
+            .all(|k| (k.hue - adjusted.hue).into_inner().abs() > MIN_HUE_DIFFERENCE);
+        if distinct && !is_near_avoid(adjusted, avoid, &bg_config.avoid_tolerance) {
+            candidates.push(adjusted);
+        }
+    }
+    candidates.into_iter().map(|c| Srgb::from_color(fit_gamut(c, bg_config.gamut_map))).collect()
+}
+
+/// Saves the candidates of the theme written for the mode, replacing those of
+/// the previous wallpaper.
+pub fn save(is_dark: bool, candidates: &[Srgb]) {
+    let res = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)
+        .and_then(|c| c.set(cache::rotation_key(is_dark), candidates.to_vec()));
+    if let Err(err) = res {
+        tracing::error!("Failed to save the accent candidates: {}", err);
+    }
+}
+
+/// How often the accent is rotated, from `rotate_accent_secs`
+pub fn period() -> Option<Duration> {
+    load_config().rotate_accent_secs.filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
+/// Applies the `step`th saved candidate of each mode as the accent, keeping
+/// the rest of the current theme.
+pub async fn apply(conn: &Connection, step: usize) {
+    let bg_config = load_config();
+    if !bg_config.write_fields.accent {
+        return;
+    }
+    for is_dark in [true, false] {
+        match apply_mode(is_dark, step, &bg_config) {
+            Ok(Some(result)) => {
+                if let Err(err) = emit_theme_generated(conn, &result, is_dark).await {
+                    tracing::error!("Failed to emit the ThemeGenerated signal: {}", err);
+                }
+                if let Some(cmd) = &bg_config.post_apply_cmd {
+                    spawn_post_apply_cmd(cmd, &result, is_dark);
+                }
+            },
+            Ok(None) => {},
+            Err(err) => tracing::error!("Failed to rotate the accent: {}", err),
+        }
+    }
+}
+
+fn apply_mode(
+    is_dark: bool,
+    step: usize,
+    bg_config: &MyConfig,
+) -> anyhow::Result<Option<BgResult>> {
+    let state_config = cosmic_config::Config::new_state(ID, cache::STATE_VERSION)?;
+    let candidates =
+        state_config.get::<Vec<Srgb>>(cache::rotation_key(is_dark)).unwrap_or_default();
+    if candidates.len() < 2 {
+        return Ok(None);
+    }
+    let accent = candidates[step % candidates.len()];
+
+    let (builder_config, default) = if is_dark {
+        (ThemeBuilder::dark_config()?, Theme::dark_default())
+    } else {
+        (ThemeBuilder::light_config()?, Theme::light_default())
+    };
+    let mut t = ThemeBuilder::get_entry(&builder_config).unwrap_or_else(|(_, entry)| entry);
+    t.accent = Some(accent);
+    if bg_config.harmonize_palette && bg_config.write_fields.palette {
+        sync_palette(&mut t, accent, bg_config);
+    }
+    let result = BgResult {
+        accent,
+        bg: t.bg_color.unwrap_or(default.background.base),
+        neutral: t.neutral_tint.unwrap_or(default.palette.neutral_5.into_color()),
+        text: t.text_tint,
+    };
+    write_theme(t, is_dark, bg_config)?;
+    tracing::info!(
+        "Rotated the {} accent to {}",
+        if is_dark { "dark" } else { "light" },
+        crate::hex(accent)
+    );
+    Ok(Some(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_get_the_lightness_bias() {
+        let bg = Lch::from_color(Srgb::new(0.1, 0.1, 0.1));
+        let fallback = Lch::from_color(Srgb::new(0.39, 0.81, 1.));
+        let accent = Srgb::new(0.9, 0.2, 0.1);
+        let pool = [Lab::from_color(Lch::new(50., 30., 130.))];
+        let unbiased = MyConfig::default();
+        let biased = MyConfig { accent_lightness_bias_dark: 10., ..Default::default() };
+
+        let [_, plain] = candidates(accent, &pool, bg, fallback, true, &[], &unbiased)[..] else {
+            panic!("expected two candidates");
+        };
+        let [_, lighter] = candidates(accent, &pool, bg, fallback, true, &[], &biased)[..] else {
+            panic!("expected two candidates");
+        };
+        assert!(Lch::from_color(lighter).l > Lch::from_color(plain).l);
+        // the light mode has its own bias
+        let light = candidates(accent, &pool, bg, fallback, false, &[], &biased);
+        assert_eq!(light[1], plain);
+    }
+}
@@ -0,0 +1,86 @@
+//! A cheap saliency heuristic for picking the interesting part of a wallpaper
+
+use palette::Lab;
+
+/// Cells per side of the analysis grid
+const GRID: usize = 4;
+/// Cells per side of the crop
+const CROP_CELLS: usize = 2;
+
+/// Crops the sampled pixels, `width` per row, to the window of the grid with
+/// the most color variance, returning the crop and its width.
+///
+/// Wallpapers are often mostly uniform, with the detail that gives them their
+/// character in one part. Samples too small for the grid are returned as is.
+pub fn entropy_crop(pixels: &[Lab], width: usize) -> (Vec<Lab>, usize) {
+    let height = if width == 0 { 0 } else { pixels.len() / width };
+    let (cell_w, cell_h) = (width / GRID, height / GRID);
+    if cell_w == 0 || cell_h == 0 {
+        return (pixels.to_vec(), width);
+    }
+
+    let mut variance = [[0_f32; GRID]; GRID];
+    for (cy, row) in variance.iter_mut().enumerate() {
+        for (cx, v) in row.iter_mut().enumerate() {
+            *v = cell_variance(pixels, width, cx * cell_w, cy * cell_h, cell_w, cell_h);
+        }
+    }
+
+    let mut best = (0, 0, f32::MIN);
+    for cy in 0..=GRID - CROP_CELLS {
+        for cx in 0..=GRID - CROP_CELLS {
+            let sum: f32 = variance[cy..cy + CROP_CELLS]
+                .iter()
+                .flat_map(|row| &row[cx..cx + CROP_CELLS])
+                .sum();
+            if sum > best.2 {
+                best = (cx, cy, sum);
+            }
+        }
+    }
+
+    let (x0, y0) = (best.0 * cell_w, best.1 * cell_h);
+    let (crop_w, crop_h) = (cell_w * CROP_CELLS, cell_h * CROP_CELLS);
+    let crop = (y0..y0 + crop_h)
+        .flat_map(|y| &pixels[y * width + x0..y * width + x0 + crop_w])
+        .copied()
+        .collect();
+    (crop, crop_w)
+}
+
+/// The summed variance of the Lab components of a cell
+fn cell_variance(pixels: &[Lab], width: usize, x0: usize, y0: usize, w: usize, h: usize) -> f32 {
+    let cell = || (y0..y0 + h).flat_map(move |y| &pixels[y * width + x0..y * width + x0 + w]);
+    let n = (w * h) as f32;
+    let (l, a, b) = cell().fold((0., 0., 0.), |(l, a, b), c| (l + c.l, a + c.a, b + c.b));
+    let mean = Lab::new(l / n, a / n, b / n);
+    cell()
+        .map(|c| (c.l - mean.l).powi(2) + (c.a - mean.a).powi(2) + (c.b - mean.b).powi(2))
+        .sum::<f32>()
+        / n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crops_to_the_detailed_corner() {
+        // uniform grey, with a checkerboard in the bottom right quarter
+        let width = 8;
+        let pixels: Vec<Lab> = (0..64)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                if x >= 4 && y >= 4 && (x + y) % 2 == 0 {
+                    Lab::new(90., 40., 40.)
+                } else {
+                    Lab::new(50., 0., 0.)
+                }
+            })
+            .collect();
+        let (crop, crop_width) = entropy_crop(&pixels, width);
+        assert_eq!(crop_width, 4);
+        assert_eq!(crop.len(), 16);
+        assert_eq!(crop[0], Lab::new(90., 40., 40.));
+    }
+}
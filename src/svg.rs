@@ -0,0 +1,42 @@
+//! Theming from SVG wallpapers through a rasterized sample
+
+use std::path::Path;
+
+use image::DynamicImage;
+
+const SVG_EXTENSIONS: &[&str] = &["svg", "svgz"];
+
+/// Whether the wallpaper is an SVG, judging by its extension
+pub fn is_svg(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SVG_EXTENSIONS.iter().any(|v| ext.eq_ignore_ascii_case(v)))
+}
+
+/// Rasterizes the SVG scaled to `width`, keeping its aspect ratio, so that it
+/// takes the same path as a decoded image that was already downscaled.
+///
+/// Transparent areas are rendered black, which is how they show behind the
+/// wallpaper.
+#[cfg(feature = "svg")]
+pub fn rasterize(path: &Path, width: u32) -> anyhow::Result<DynamicImage> {
+    use resvg::{tiny_skia, usvg};
+
+    let tree = usvg::Tree::from_data(&std::fs::read(path)?, &usvg::Options::default())?;
+    let size = tree.size();
+    let scale = width.max(1) as f32 / size.width();
+    let height = (size.height() * scale).round().max(1.) as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width.max(1), height)
+        .ok_or_else(|| anyhow::anyhow!("Invalid SVG sample size {width}x{height}"))?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    let img = image::RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.take())
+        .ok_or_else(|| anyhow::anyhow!("The rasterized SVG has the wrong size"))?;
+    // the pixels are premultiplied, so dropping the alpha blends onto black
+    Ok(DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()))
+}
+
+#[cfg(not(feature = "svg"))]
+pub fn rasterize(_path: &Path, _width: u32) -> anyhow::Result<DynamicImage> {
+    anyhow::bail!("SVG wallpapers require the svg feature")
+}
@@ -0,0 +1,155 @@
+//! Parsing of the system config, one key at a time
+
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeSeed, Deserializer, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
+use serde::Deserialize;
+
+use crate::MyConfig;
+
+/// Parses the system config, a RON struct with any subset of the config keys.
+///
+/// Each key is parsed on its own, so that a malformed key only falls back to
+/// its default instead of discarding the whole file. The errors of the keys
+/// that couldn't be parsed are returned with the config.
+pub fn parse(s: &str) -> (MyConfig, Vec<String>) {
+    let keys = match ron::from_str::<ron::Value>(s) {
+        Ok(ron::Value::Map(map)) => map.keys().cloned().collect::<Vec<_>>(),
+        // not a struct, report the error of the full parse
+        _ => {
+            return match ron::from_str::<MyConfig>(s) {
+                Ok(config) => (config, Vec::new()),
+                Err(err) => (MyConfig::default(), vec![err.to_string()]),
+            };
+        },
+    };
+    let Ok(serde_json::Value::Object(mut config)) = serde_json::to_value(MyConfig::default())
+    else {
+        return (MyConfig::default(), vec!["Failed to serialize the default config".into()]);
+    };
+
+    let mut errs = Vec::new();
+    for key in keys {
+        let ron::Value::String(name) = key else {
+            errs.push(format!("Invalid key {key:?}"));
+            continue;
+        };
+        if !config.contains_key(&name) {
+            errs.push(format!("Unknown key {name}"));
+            continue;
+        }
+        // a config with only this key set
+        let parsed = parse_key(s, &name)
+            .and_then(|c| serde_json::to_value(c).map_err(|err| err.to_string()));
+        match parsed {
+            Ok(serde_json::Value::Object(mut parsed)) => {
+                if let Some(value) = parsed.remove(&name) {
+                    config.insert(name, value);
+                }
+            },
+            Ok(_) => {},
+            Err(err) => errs.push(format!("{name}: {err}")),
+        }
+    }
+
+    match serde_json::from_value(serde_json::Value::Object(config)) {
+        Ok(config) => (config, errs),
+        Err(err) => {
+            errs.push(err.to_string());
+            (MyConfig::default(), errs)
+        },
+    }
+}
+
+/// Parses the config with only `key` set, skipping the values of the other
+/// keys.
+fn parse_key(s: &str, key: &str) -> Result<MyConfig, String> {
+    let mut de = ron::Deserializer::from_str(s).map_err(|err| err.to_string())?;
+    de.deserialize_any(KeyVisitor(key)).map_err(|err| err.to_string())
+}
+
+/// Visits the struct of the config, deserializing the value of one key into
+/// a config and ignoring the others
+struct KeyVisitor<'a>(&'a str);
+
+impl<'de> Visitor<'de> for KeyVisitor<'_> {
+    type Value = MyConfig;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a config struct")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MyConfig, A::Error> {
+        let mut config = MyConfig::default();
+        // struct keys are identifiers, which only deserialize as values
+        while let Some(key) = map.next_key::<ron::Value>()? {
+            if key == ron::Value::String(self.0.to_string()) {
+                config = map.next_value_seed(KeyVisitor(self.0))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for KeyVisitor<'_> {
+    type Value = MyConfig;
+
+    fn deserialize<D: Deserializer<'de>>(self, value: D) -> Result<MyConfig, D::Error> {
+        MyConfig::deserialize(MapAccessDeserializer::new(OneKey {
+            key: Some(self.0),
+            value: Some(value),
+        }))
+    }
+}
+
+/// A map with the one key of a config and the deserializer of its value
+struct OneKey<'a, D> {
+    key: Option<&'a str>,
+    value: Option<D>,
+}
+
+impl<'de, D: Deserializer<'de>> MapAccess<'de> for OneKey<'_, D> {
+    type Error = D::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, D::Error> {
+        self.key.take().map(|key| seed.deserialize(key.into_deserializer())).transpose()
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, D::Error> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value),
+            None => Err(serde::de::Error::custom("the value was already read")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contrast::ContrastModel;
+
+    #[test]
+    fn bad_key_keeps_the_others() {
+        let (config, errs) = parse(
+            "// site defaults
+            (
+                randomize: false,
+                sample_width: \"wide\",
+                contrast_model: Apca,
+                video_frame_cmd: Some(r\"grab (first) frame), %o\"),
+                avoid_light: [(red: 0.5, green: 0.5, blue: 0.5)],
+            )",
+        );
+        assert!(!config.randomize);
+        assert_eq!(config.sample_width, MyConfig::default().sample_width);
+        assert_eq!(config.contrast_model, ContrastModel::Apca);
+        assert_eq!(config.avoid_light.len(), 1);
+        assert_eq!(config.video_frame_cmd.as_deref(), Some("grab (first) frame), %o"));
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].starts_with("sample_width"));
+    }
+}
@@ -0,0 +1,51 @@
+//! Lookup of the wallpaper thumbnails cached by the desktop
+
+use std::path::{Path, PathBuf};
+
+/// The thumbnail sizes of the freedesktop.org thumbnail cache, smallest first
+const SIZES: &[&str] = &["normal", "large", "x-large", "xx-large"];
+
+/// The smallest cached thumbnail of `path` at least `width` pixels wide, if
+/// it is newer than the wallpaper.
+///
+/// Thumbnails are looked up in `$XDG_CACHE_HOME/thumbnails` as described by
+/// the freedesktop.org thumbnail specification.
+pub fn find(path: &Path, width: u32) -> Option<PathBuf> {
+    let cache_home = crate::cache::cache_home()?;
+    let path = std::fs::canonicalize(path).ok()?;
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+    let name = format!("{:x}.png", md5::compute(file_uri(&path)));
+
+    SIZES.iter().map(|size| cache_home.join("thumbnails").join(size).join(&name)).find(|thumb| {
+        let fresh = std::fs::metadata(thumb)
+            .and_then(|m| m.modified())
+            .is_ok_and(|thumb_modified| thumb_modified >= modified);
+        fresh && image::image_dimensions(thumb).is_ok_and(|(w, _)| w >= width)
+    })
+}
+
+/// The `file://` URI of an absolute path, percent-encoded as the thumbnail
+/// cache keys it
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for b in path.as_os_str().as_encoded_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                uri.push(*b as char)
+            },
+            _ => uri.push_str(&format!("%{b:02X}")),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_is_percent_encoded() {
+        let uri = file_uri(Path::new("/home/me/My Wallpapers/été.png"));
+        assert_eq!(uri, "file:///home/me/My%20Wallpapers/%C3%A9t%C3%A9.png");
+    }
+}
@@ -0,0 +1,77 @@
+//! Theming from video wallpapers through a sampled frame
+
+use std::path::Path;
+
+use image::DynamicImage;
+
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mkv", "webm", "mov", "avi"];
+
+/// Whether the wallpaper is a video, judging by its extension
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| VIDEO_EXTENSIONS.iter().any(|v| ext.eq_ignore_ascii_case(v)))
+}
+
+/// Grabs a representative frame of the video.
+///
+/// By default the first frame is extracted with `ffmpeg`. A custom command is
+/// run with `sh -c`, with the video path in `$WALLPAPER` and the path the
+/// frame must be written to in `$FRAME`. The frame is removed once decoded.
+#[cfg(feature = "video")]
+pub fn grab_frame(path: &Path, cmd: Option<&str>) -> anyhow::Result<DynamicImage> {
+    let frame = create_frame_file()?;
+    let img = grab_frame_to(path, cmd, &frame);
+    let _ = std::fs::remove_file(&frame);
+    img
+}
+
+#[cfg(feature = "video")]
+fn grab_frame_to(path: &Path, cmd: Option<&str>, frame: &Path) -> anyhow::Result<DynamicImage> {
+    use std::process::Command;
+
+    let status = match cmd {
+        Some(cmd) => Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .env("WALLPAPER", path)
+            .env("FRAME", frame)
+            .status(),
+        None => Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y", "-i"])
+            .arg(path)
+            .args(["-frames:v", "1"])
+            .arg(frame)
+            .status(),
+    }?;
+    if !status.success() {
+        anyhow::bail!("The frame grab command failed with {status}");
+    }
+
+    Ok(image::io::Reader::open(frame)?.with_guessed_format()?.decode()?)
+}
+
+/// Creates an empty file with an unpredictable name for the frame, in the
+/// user's runtime directory when there is one.
+///
+/// The file is created exclusively, so that a file or symlink planted at the
+/// path in a shared directory is never written through, and concurrent grabs
+/// don't share a file.
+#[cfg(feature = "video")]
+fn create_frame_file() -> std::io::Result<std::path::PathBuf> {
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map_or_else(std::env::temp_dir, std::path::PathBuf::from);
+    loop {
+        let frame = dir.join(format!("{}-frame-{:016x}.png", crate::ID, rand::random::<u64>()));
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&frame) {
+            Ok(_) => return Ok(frame),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(not(feature = "video"))]
+pub fn grab_frame(_path: &Path, _cmd: Option<&str>) -> anyhow::Result<DynamicImage> {
+    anyhow::bail!("Video wallpapers require the video feature")
+}